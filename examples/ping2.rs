@@ -75,8 +75,6 @@ fn main() {
     //let mut sockets = SocketSet::new(vec![]);
     //let icmp_handle = sockets.add(icmp_socket);
 
-    let mut ping_buffer = [0u8; 2048];
-
     // Bind to ICMP messages with the ICMP identifier 0x1234
     icmp_socket.bind(IcmpEndpoint::Ident(0)).unwrap();
 
@@ -93,7 +91,7 @@ fn main() {
         };
         seq_no += 1;
 
-        request.encode::<pinglogger::icmp::IcmpV6>(&mut ping_buffer).unwrap();
+        let ping_buffer = request.encode::<pinglogger::icmp::IcmpV6>().unwrap();
         targets2.output.iter().for_each(|site| {
             let remote_addr: IpAddress = site.sock_addr.ip().into();
             if icmp_socket.can_send() {
@@ -114,7 +112,7 @@ fn main() {
                     _ => unimplemented!()
                 }
                 println!("Send {}", remote_addr);
-                icmp_socket.send_slice(&ping_buffer[..64], a).unwrap();
+                icmp_socket.send_slice(&ping_buffer, a).unwrap();
             }});
         println!("wait");
         if icmp_socket.can_recv() {
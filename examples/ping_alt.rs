@@ -21,11 +21,14 @@ use pnet::transport::{
 };
 use pnet::util;
 use pinglogger::cli;
+use pinglogger::resolver::Resolver;
 use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use std::thread;
 use std::thread::sleep;
 use std::time::Duration;
 use itertools::Itertools;
+use crossbeam_channel::unbounded;
 
 /// Checksum method for ipv6.
 /// @param packet is a reference to a `MutableIcmpv6Packet`.
@@ -69,13 +72,7 @@ fn send(sender: &mut TransportSender, address: IpAddr, seq: u16, ident: u16) ->
 }
 
 fn main() -> Result<(), Box<dyn error::Error>> {
-    let (targets, _) = cli::init();
-
-    // bail if we don't have anything
-    if targets.output.len() == 0 {
-        return Ok(());
-    }
-
+    // the channels need root or CAP_NET_RAW, which init gives up
     let channel_size = 1024;
     let ident = process::id() as u16;
 
@@ -94,11 +91,22 @@ fn main() -> Result<(), Box<dyn error::Error>> {
     let (mut tx_v6_2, mut rx_v6_2) = transport_channel(channel_size, Layer4(Ipv6(IpNextHeaderProtocols::Icmpv6)))
         .map_err(|err| format!("Error opening the channel: {}", err))?;
 
+    let (targets, options, _) = cli::init();
+
+    // bail if we don't have anything
+    if options.targets.is_empty() {
+        return Ok(());
+    }
+
+    // sites are added as the hosts resolve
+    let (events, _dns) = unbounded();
+    let resolver = Arc::new(Resolver::new(targets.clone(), options.targets.clone(), &options.resolver, events));
+    thread::spawn(move || resolver.run());
 
     thread::spawn(move || {
         let mut seq_cnt = 1;
         loop {
-            targets.output.iter().for_each(|site| {
+            targets.output.read().unwrap().iter().for_each(|site| {
                 let result = match site.sock_addr {
                     SocketAddr::V4(_) => {
                         send(&mut tx_v4_2, site.sock_addr.ip(), seq_cnt, ident)
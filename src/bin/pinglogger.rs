@@ -1,60 +1,287 @@
-use std::collections::HashMap;
-use std::error::Error;
-use std::thread::sleep;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::fs;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
-use pinglogger::pinger::UniPacket;
+use pinglogger::pinger::{Correlator, Event, PingTargets, Scheduler, UniPacket};
+use pinglogger::resolver::Resolver;
+use pinglogger::dns::{Outcome, Prober};
+use pinglogger::output::Output;
+use pinglogger::health::{self, Status, Verdict};
 
-use std::time::{Duration, SystemTime};
-use crossbeam_channel::bounded;
-use pinglogger::{cli, stats};
+use std::time::{Duration, Instant, SystemTime};
+use crossbeam_channel::{bounded, unbounded, RecvTimeoutError};
+use pinglogger::{cli, influx, prometheus, stats};
+use nix::sys::signal::{self, SigAction, SigHandler, SaFlags, SigSet, Signal};
+use log::*;
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let (targets, targets2) = cli::init();
+// How often outstanding requests are checked for expiry
+const EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+// How often the config file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sigint(_: i32) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn handle_sighup(_: i32) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+fn modified(path: &Option<String>) -> Option<SystemTime> {
+    path.as_ref().and_then(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+}
+
+fn report(event: &Event, output: &mut Output, metrics: &mut stats::Metrics, statistics: &mut stats::Statistics) {
+    statistics.record(event);
+    if let Err(e) = output.write(event) {
+        error!("Unable to write output: {}", e);
+    }
+    metrics.update(event);
+}
+
+/// Forget the statistics and series of sites no longer pinged, after their
+/// addresses change or they're dropped on reload
+fn retire(targets: &PingTargets, metrics: &mut stats::Metrics, statistics: &mut stats::Statistics) {
+    let sites: HashSet<_> = targets.output.read().unwrap().iter()
+        .map(|site| (site.host.clone(), site.sock_addr.ip()))
+        .collect();
+    statistics.retain(&sites);
+    metrics.retain(&sites);
+}
+
+/// Replace the verdict file in one go, so readers never see half of it
+fn write_verdict(path: &str, verdict: &Verdict) {
+    let tmp = format!("{}.tmp", path);
+    if let Err(e) = fs::write(&tmp, verdict.json() + "\n").and_then(|_| fs::rename(&tmp, path)) {
+        warn!("Unable to write verdict to {}: {}", path, e);
+    }
+}
+
+/// Log hosts whose health changed since the last verdict
+fn transitions(verdict: &Verdict, last: &mut HashMap<String, Status>) {
+    for host in &verdict.hosts {
+        let before = last.insert(host.host.clone(), host.status).unwrap_or(Status::Up);
+        if host.status > before {
+            warn!("{} is {}: {}", host.host, host.status.name(), host.reasons.join(", "));
+        } else if host.status < before {
+            info!("{} is {}", host.host, host.status.name());
+        }
+    }
+    last.retain(|host, _| verdict.hosts.iter().any(|h| h.host == *host));
+}
+
+// Unable to start or carry on, so exit like cli::init does with bad options
+fn or_exit<T, E: Display>(result: Result<T, E>, what: &str) -> T {
+    result.unwrap_or_else(|e| {
+        error!("{}: {}", what, e);
+        process::exit(2);
+    })
+}
+
+fn main() {
+    let (targets, options, matches) = cli::init();
+    let start_instant = targets.start_instant;
 
     let (s, r) = bounded(50);
     let s2 = s.clone();
 
-    // bail if we don't have anything
-    if targets.output.len() == 0 {
-        return Ok(());
+    // bail if we don't have anything, and nothing to reload
+    if options.targets.is_empty() && options.config.is_none() {
+        error!("No hosts to ping, give some or a config file with --config");
+        process::exit(2);
     }
 
-    let mut metrics = stats::metrics("app");
+    let output_file = options.output.file.as_deref();
+    let mut output = or_exit(Output::new(options.output.format, output_file),
+        &format!("Unable to open output {}", output_file.unwrap_or("-")));
+    let mut metrics = stats::Metrics::default();
+    if let Some(sink) = stats::sink(&options.metrics) {
+        metrics.sinks.push(sink);
+    }
+    if let Some(addr) = &options.prometheus {
+        metrics.add(or_exit(prometheus::prometheus(addr), &format!("Unable to serve prometheus on {}", addr)));
+    }
+    if let Some(url) = &options.influx.url {
+        metrics.add(or_exit(influx::influx(url, &options.influx.measurement), &format!("Unable to write to influx at {}", url)));
+    }
+    metrics.targets(&options.targets);
+    let mut statistics = stats::Statistics::new(options.window);
+
+    let action = SigAction::new(SigHandler::Handler(handle_sigint), SaFlags::empty(), SigSet::empty());
+    or_exit(unsafe { signal::sigaction(Signal::SIGINT, &action) }, "Unable to handle SIGINT");
+    let action = SigAction::new(SigHandler::Handler(handle_sighup), SaFlags::empty(), SigSet::empty());
+    or_exit(unsafe { signal::sigaction(Signal::SIGHUP, &action) }, "Unable to handle SIGHUP");
 
+    let (dns_s, dns_r) = unbounded();
+    let resolver = Arc::new(Resolver::new(targets.clone(), options.targets.clone(), &options.resolver, dns_s));
+    let r2 = resolver.clone();
+    thread::spawn(move || r2.run());
+
+    let (lookup_s, lookup_r) = unbounded();
+    let prober = if options.dns.enabled {
+        let prober = Arc::new(Prober::new(&options.dns, options.targets.clone(), lookup_s));
+        let p2 = prober.clone();
+        thread::spawn(move || p2.run());
+        Some(prober)
+    } else {
+        None
+    };
+
+    let sender = targets.clone();
+    let count = options.count;
     thread::spawn(move || {
-        let mut count = 0;
+        let mut scheduler = Scheduler::new(count);
         loop {
-            targets.ping(count, &s);
-            sleep(Duration::from_secs(1));
-            count += 1;
+            let next = {
+                let sites = sender.output.read().unwrap();
+                for (i, seq) in scheduler.due(&sites, Instant::now()) {
+                    sender.ping_site(&sites[i], seq, &s);
+                }
+                scheduler.next()
+            };
+            // woken early when the sites change
+            let wait = next.map(|t| t.saturating_duration_since(Instant::now())).unwrap_or(Duration::from_secs(1));
+            sender.wait(wait);
         }
     });
 
+    let receiver = targets.clone();
     thread::spawn(move || {
-        targets2.poll(&s2).unwrap();
+        or_exit(receiver.poll(&s2), "Unable to receive replies");
     });
 
-    let mut h = HashMap::new();
-    r.iter().for_each(|x| {
-        match x {
-            UniPacket::SendPacket {host, addr, seq, ident, t} => {
-                h.insert( (ident, seq), (host, addr, t ));
+    let deadline = options.deadline.map(|d| Instant::now() + d);
+    let mut current = options.targets.clone();
+    let mut thresholds = options.thresholds;
+    let verdict_file = options.verdict.clone().filter(|path| path != "-");
+    let mut health = HashMap::new();
+    // replies that failed the payload checks, reported once a second
+    let mut rejected = 0;
+    let mut reported = 0;
+    let mut last_rejected = None;
+
+    let mut correlator = Correlator::new();
+    let mut config_modified = modified(&options.config);
+    let mut last_watch = Instant::now();
+    loop {
+        match r.recv_timeout(EXPIRE_INTERVAL) {
+            Ok(UniPacket::Rejected { addr, reason }) => {
+                metrics.rejected(addr, reason);
+                rejected += 1;
+                last_rejected = Some((addr, reason));
+            }
+            Ok(packet) => {
+                if let UniPacket::SendPacket {host, addr, ..} = &packet {
+                    metrics.sent(host, *addr);
+                    statistics.sent(host, *addr);
+                }
+                if let Some(event) = correlator.push(packet) {
+                    report(&event, &mut output, &mut metrics, &mut statistics);
+                }
             },
-            UniPacket::RecvPacket {seq, ident, t, ttl, size} => {
-                match h.remove( &(ident, seq) ) {
-                    Some( (host, addr, t2) ) => {
-                        let d = Duration::from_nanos( (t - t2) as u64);
-                        let t = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_micros();
-                        println!("[{:.6}] {} bytes from {} ({}): icmp_seq={} ttl={} time={:.4?}", 
-                            (t as f64)/1_000_000., size, host.to_string(), addr, seq, ttl, d);
-                        metrics.update(&d, &*host);
-                    },
-                    None => {}
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => break
+        }
+
+        for event in correlator.expire(start_instant.elapsed().as_nanos()) {
+            report(&event, &mut output, &mut metrics, &mut statistics);
+        }
+        for event in dns_r.try_iter() {
+            warn!("{}", event);
+            metrics.dns(&event);
+        }
+        for lookup in lookup_r.try_iter() {
+            match lookup.outcome {
+                Outcome::Answer(_) if !lookup.changed => debug!("{}", lookup),
+                Outcome::Answer(_) => info!("{}", lookup),
+                _ => warn!("{}", lookup)
+            }
+            metrics.lookup(&lookup);
+        }
+        metrics.flush();
+
+        if last_watch.elapsed() >= WATCH_INTERVAL {
+            last_watch = Instant::now();
+            let m = modified(&options.config);
+            if m != config_modified {
+                config_modified = m;
+                info!("Config file changed");
+                RELOAD.store(true, Ordering::SeqCst);
+            }
+
+            if let Some((addr, reason)) = last_rejected.filter(|_| rejected > reported) {
+                warn!("Rejected {} replies that failed the payload checks, the last from {} ({})", rejected - reported, addr, reason.name());
+                reported = rejected;
+            }
+
+            retire(&targets, &mut metrics, &mut statistics);
+            // health over the statistics window, for long running use
+            let verdict = health::evaluate(&current, &thresholds, &statistics, &resolver.pending(), Some(Instant::now()));
+            transitions(&verdict, &mut health);
+            if let Some(path) = &verdict_file {
+                write_verdict(path, &verdict);
+            }
+        }
+
+        if RELOAD.swap(false, Ordering::SeqCst) {
+            // only the targets are reloaded, sinks keep their settings
+            match cli::options(&matches) {
+                Ok(options) => {
+                    info!("Reloaded config with {} targets", options.targets.len());
+                    current = options.targets.clone();
+                    thresholds = options.thresholds;
+                    metrics.targets(&options.targets);
+                    if let Some(prober) = &prober {
+                        prober.set_targets(options.targets.clone());
+                    }
+                    resolver.set_targets(options.targets);
                 }
+                Err(e) => error!("Reload failed, keeping the current targets: {}", e)
             }
         }
-    });
 
-    Ok(())
-}
+        let out_of_time = deadline.map(|d| Instant::now() >= d).unwrap_or(false);
+        // every address has had its requests, and they've all been answered or timed out
+        let all_sent = options.count.map(|count| {
+            resolver.settled() && correlator.outstanding() == 0 &&
+                targets.output.read().unwrap().iter().all(|site| {
+                    statistics.get(&site.host, site.sock_addr.ip()).map(|s| s.transmitted() >= count).unwrap_or(false)
+                })
+        }).unwrap_or(false);
+
+        if INTERRUPTED.load(Ordering::SeqCst) || out_of_time || all_sent {
+            retire(&targets, &mut metrics, &mut statistics);
+            statistics.print_summary();
+            for host in resolver.pending() {
+                println!("--- {} was never resolved ---", host);
+            }
+            if rejected > 0 {
+                println!("--- {} replies rejected ---", rejected);
+            }
+            break;
+        }
+    }
 
+    // judge the whole run, like ping failing unless hosts replied
+    let verdict = health::evaluate(&current, &thresholds, &statistics, &resolver.pending(), None);
+    match options.verdict.as_deref() {
+        Some("-") => println!("{}", verdict.json()),
+        Some(path) => write_verdict(path, &verdict),
+        None => {}
+    }
+    if verdict.status != Status::Up {
+        let reasons = verdict.hosts.iter()
+            .filter(|h| h.status != Status::Up)
+            .map(|h| format!("{} {} ({})", h.host, h.status.name(), h.reasons.join(", ")))
+            .chain(verdict.reasons.iter().cloned())
+            .collect::<Vec<_>>();
+        info!("Run {}: {}", verdict.status.name(), reasons.join("; "));
+    }
+    process::exit(verdict.status.exit_code());
+}
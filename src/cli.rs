@@ -1,8 +1,68 @@
-use clap::{Arg, App};
-use log::LevelFilter;
-use crate::pinger::{SelectVersion, generate_targets, PingTargets};
+use std::error::Error;
+use std::fs;
+use std::net::SocketAddr;
+use std::process;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use clap::{Arg, App, ArgMatches};
+use log::*;
+use crate::pinger::PingTargets;
+use crate::stats::{Backend, MetricsConfig, Window};
+use crate::config::{seconds, Config, DnsConfig, Family, Fill, Select, InfluxConfig, OutputConfig, ResolverConfig, Target, MIN_INTERVAL};
+use crate::output::Format;
+use crate::hosts;
+use crate::health::Thresholds;
+use crate::privileges;
+use crate::icmp::{IcmpV4, IcmpV6, Payload, Proto};
 
-pub fn init() -> (PingTargets, PingTargets) {
+pub struct Options {
+    /// Path of the config file, if there is one
+    pub config: Option<String>,
+    pub targets: Vec<Target>,
+    pub window: Window,
+    pub prometheus: Option<String>,
+    pub metrics: MetricsConfig,
+    pub influx: InfluxConfig,
+    pub output: OutputConfig,
+    pub resolver: ResolverConfig,
+    pub dns: DnsConfig,
+    /// Stop after sending this many echo requests to each address
+    pub count: Option<u64>,
+    /// Stop after this long
+    pub deadline: Option<Duration>,
+    /// Limits on all the targets together
+    pub thresholds: Thresholds,
+    /// Where to write the verdict as JSON, `-` for stdout
+    pub verdict: Option<String>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    /// Secret to sign payloads with, given in the config
+    pub key: Option<String>,
+    /// File holding the secret, read by `init` while it may still need root
+    pub key_file: Option<String>,
+}
+
+// The secret to sign payloads with, if there is one. It's only read at
+// startup, before privileges are dropped, as it's often readable by root alone.
+fn read_key(options: &Options) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let key = match &options.key_file {
+        Some(path) => {
+            let key = fs::read_to_string(path).map_err(|e| format!("Unable to read key {}: {}", path, e))?;
+            Some(key.trim_end().as_bytes().to_vec())
+        }
+        None => options.key.as_ref().map(|key| key.as_bytes().to_vec())
+    };
+    if key.as_ref().map(|key| key.is_empty()).unwrap_or(false) {
+        return Err("The payload key is empty".into());
+    }
+    Ok(key)
+}
+
+/// Parse the command line and the config file, open the sockets and drop
+/// privileges. The matches are returned so the options can be reloaded with
+/// `options`.
+pub fn init() -> (Arc<PingTargets>, Options, ArgMatches<'static>) {
     simple_logger::init().unwrap();
     let matches = App::new("ping")
         .version("1.0")
@@ -14,20 +74,187 @@ pub fn init() -> (PingTargets, PingTargets) {
             .multiple(false)
             .long("listen")
             .help("listen and dump packets"))
+        .arg(Arg::with_name("CONFIG")
+            .long("config")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Read targets and settings from a TOML file, other options override it"))
+        .arg(Arg::with_name("FILE")
+            .short("f")
+            .long("file")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Read more hosts from FILE, one per line with optional key=value labels"))
+        .arg(Arg::with_name("MAX_RANGE")
+            .long("max-range")
+            .takes_value(true)
+            .help("Most addresses CIDR ranges like 192.0.2.0/24 may expand to, in all [default: 1024]"))
         .arg(Arg::with_name("4")
             .short("4")
             .help("IPV4"))
         .arg(Arg::with_name("6")
             .short("6")
             .help("IPV6"))
+        .arg(Arg::with_name("SELECT")
+            .long("select")
+            .takes_value(true)
+            .value_name("POLICY")
+            .help("Which addresses of each host to ping: all, first, per-family or random:N [default: all]"))
         .arg(Arg::with_name("v")
             .short("v")
             .multiple(true)
             .help("Sets the level of verbosity"))
+        .arg(Arg::with_name("COUNT")
+            .short("c")
+            .long("count")
+            .takes_value(true)
+            .help("Stop after sending COUNT echo requests to each address and waiting for the replies"))
+        .arg(Arg::with_name("DEADLINE")
+            .short("w")
+            .long("deadline")
+            .takes_value(true)
+            .value_name("SECS")
+            .help("Stop after SECS seconds, however many requests have been sent"))
+        .arg(Arg::with_name("MAX_LOSS")
+            .long("max-loss")
+            .takes_value(true)
+            .value_name("PCT")
+            .help("Targets losing more than PCT percent of requests are degraded"))
+        .arg(Arg::with_name("MAX_AVG")
+            .long("max-avg")
+            .takes_value(true)
+            .value_name("MS")
+            .help("Targets with an average rtt over MS milliseconds are degraded"))
+        .arg(Arg::with_name("MAX_P99")
+            .long("max-p99")
+            .takes_value(true)
+            .value_name("MS")
+            .help("Targets with a 99th percentile rtt over MS milliseconds are degraded"))
+        .arg(Arg::with_name("VERDICT")
+            .long("verdict")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write the health verdict as JSON to FILE, updated every second, or to stdout at exit with -"))
+        .arg(Arg::with_name("TIMEOUT")
+            .short("W")
+            .long("timeout")
+            .takes_value(true)
+            .help("Time to wait for a reply, in milliseconds [default: 1000]"))
+        .arg(Arg::with_name("INTERVAL")
+            .short("i")
+            .long("interval")
+            .takes_value(true)
+            .help("Seconds between echo requests to each target, at least 0.01 [default: 1]"))
+        .arg(Arg::with_name("SIZE")
+            .short("s")
+            .long("size")
+            .takes_value(true)
+            .help("Bytes of payload in each echo request, at least 33, or 49 with --key-file, up to 65507, or 65527 with -6 [default: 56]"))
+        .arg(Arg::with_name("FILL")
+            .short("p")
+            .long("pattern")
+            .takes_value(true)
+            .help("Fill the rest of the payload with zeros, random bytes, or up to 16 bytes of hex like ff00 [default: zeros]"))
+        .arg(Arg::with_name("DONT_FRAGMENT")
+            .short("M")
+            .long("dont-fragment")
+            .help("Set the Don't Fragment bit, so packets too big for the path are lost instead of fragmented"))
+        .arg(Arg::with_name("WINDOW_COUNT")
+            .long("window-count")
+            .takes_value(true)
+            .help("Number of recent probes in the rolling statistics [default: 100]"))
+        .arg(Arg::with_name("WINDOW_TIME")
+            .long("window-time")
+            .takes_value(true)
+            .help("Age in seconds of recent probes in the rolling statistics [default: 60]"))
+        .arg(Arg::with_name("RESOLVE_INTERVAL")
+            .long("resolve-interval")
+            .takes_value(true)
+            .value_name("SECS")
+            .help("Seconds between DNS lookups of each host, 0 to only look up at startup and reload [default: 300]"))
+        .arg(Arg::with_name("DNS_PROBE")
+            .long("dns-probe")
+            .help("Also look up the A and AAAA records of each host, recording latency and failures"))
+        .arg(Arg::with_name("DNS_SERVER")
+            .long("dns-server")
+            .takes_value(true)
+            .value_name("ADDR")
+            .help("Nameserver for --dns-probe, as ip:port [default: first in /etc/resolv.conf]"))
+        .arg(Arg::with_name("DNS_INTERVAL")
+            .long("dns-interval")
+            .takes_value(true)
+            .value_name("SECS")
+            .help("Seconds between DNS probes of each host [default: 30]"))
+        .arg(Arg::with_name("PROMETHEUS")
+            .long("prometheus")
+            .takes_value(true)
+            .value_name("ADDR")
+            .help("Serve Prometheus metrics on ADDR (e.g. 0.0.0.0:9100)"))
+        .arg(Arg::with_name("METRICS")
+            .long("metrics")
+            .takes_value(true)
+            .possible_values(&["statsd", "graphite", "none"])
+            .help("Metrics server to send results to, defaults to statsd unless --prometheus is given"))
+        .arg(Arg::with_name("METRICS_HOST")
+            .long("metrics-host")
+            .takes_value(true)
+            .help("Host of the metrics server [default: localhost]"))
+        .arg(Arg::with_name("METRICS_PORT")
+            .long("metrics-port")
+            .takes_value(true)
+            .help("Port of the metrics server, defaults to 8125 for statsd and 2003 for graphite (2004 with --graphite-pickle)"))
+        .arg(Arg::with_name("GRAPHITE_PICKLE")
+            .long("graphite-pickle")
+            .help("Send batches to graphite with the pickle protocol instead of plaintext"))
+        .arg(Arg::with_name("METRICS_PREFIX")
+            .long("metrics-prefix")
+            .takes_value(true)
+            .help("Prefix for metric names [default: app]"))
+        .arg(Arg::with_name("INFLUX")
+            .long("influx")
+            .takes_value(true)
+            .value_name("URL")
+            .help("Write points to InfluxDB at udp://host:port or http://host:port/write?db=name"))
+        .arg(Arg::with_name("INFLUX_MEASUREMENT")
+            .long("influx-measurement")
+            .takes_value(true)
+            .help("Measurement name for InfluxDB points [default: ping]"))
+        .arg(Arg::with_name("FORMAT")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["text", "json", "csv"])
+            .help("Format of probe results [default: text]"))
+        .arg(Arg::with_name("OUTPUT")
+            .short("o")
+            .long("output")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Append probe results to FILE instead of stdout"))
+        .arg(Arg::with_name("KEY_FILE")
+            .long("key-file")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Sign payloads with the secret in FILE, and reject replies that aren't signed with it"))
+        .arg(Arg::with_name("USER")
+            .long("user")
+            .takes_value(true)
+            .help("Run as USER once the sockets are open, when started as root"))
+        .arg(Arg::with_name("GROUP")
+            .long("group")
+            .takes_value(true)
+            .help("Run as GROUP once the sockets are open, defaults to the user's group"))
         .arg(Arg::with_name("HOST")
-            .help("Sets the input file to use")
+            .help("Hosts, addresses or CIDR ranges to ping, in addition to any in the config file")
             .multiple(true)
-        ).get_matches();
+        ).get_matches_safe().unwrap_or_else(|e| {
+            // --help and --version exit with 0, mistakes are bad options
+            if !e.use_stderr() {
+                e.exit();
+            }
+            eprintln!("{}", e.message);
+            process::exit(2);
+        });
 
     let verbose = matches.occurrences_of("v");
     if verbose > 0 {
@@ -36,24 +263,207 @@ pub fn init() -> (PingTargets, PingTargets) {
         log::set_max_level(LevelFilter::Info);
     }
 
-    let hosts: Vec<_> = match matches.values_of("HOST") {
-        Some(x) => x.collect(),
-        None => Vec::new()
+    let options = match options(&matches) {
+        Ok(options) => options,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(2);
+        }
     };
 
-    let mut versions: Vec<SelectVersion> = vec![];
+    // sites are added as the resolver looks the hosts up
+    let key = match read_key(&options) {
+        Ok(key) => key,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(2);
+        }
+    };
+    let mut targets = match PingTargets::new(key) {
+        Ok(targets) => targets,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(2);
+        }
+    };
+    // before any threads start, as capabilities are per thread
+    if let Err(e) = privileges::drop(options.user.as_deref(), options.group.as_deref()) {
+        error!("{}", e);
+        process::exit(2);
+    }
+    targets.start();
+    (Arc::new(targets), options, matches)
+}
 
-    if matches.occurrences_of("6") > 0 {
-        versions.push(SelectVersion::V6);
+// Parse an option if it was given
+fn value<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>, String> {
+    match matches.value_of(name) {
+        Some(v) => v.parse::<T>().map(Some).map_err(|_| format!("Invalid value for {}: {}", name, v)),
+        None => Ok(None)
     }
+}
 
-    if matches.occurrences_of("4") > 0 {
-        versions.push(SelectVersion::V4);
+// Parse a duration option if it was given, in seconds times scale
+fn duration(matches: &ArgMatches, name: &str, scale: f64) -> Result<Option<Duration>, String> {
+    match value::<f64>(matches, name)? {
+        Some(v) => seconds::from_f64(v * scale).map(Some)
+            .map_err(|_| format!("Invalid value for {}: {}", name, matches.value_of(name).unwrap_or_default())),
+        None => Ok(None)
     }
+}
 
-    let mut targets = generate_targets(hosts.clone(), &versions).unwrap();
-    let mut targets2 = generate_targets(hosts.clone(), &versions).unwrap();
-    targets.start();
-    targets2.start();
-    (targets, targets2)
+/// Read the config file, if any, and apply the command line over it
+pub fn options(matches: &ArgMatches) -> Result<Options, Box<dyn Error>> {
+    let mut config = match matches.value_of("CONFIG") {
+        Some(path) => Config::load(path)?,
+        None => Config::default()
+    };
+
+    // command line options override the config file
+    if matches.occurrences_of("6") > 0 && matches.occurrences_of("4") == 0 {
+        config.defaults.family = Family::Ipv6;
+    } else if matches.occurrences_of("4") > 0 && matches.occurrences_of("6") == 0 {
+        config.defaults.family = Family::Ipv4;
+    }
+    if let Some(select) = value::<Select>(matches, "SELECT")? {
+        config.defaults.select = select;
+    }
+    if let Some(loss) = value::<f64>(matches, "MAX_LOSS")? {
+        config.defaults.thresholds.max_loss = Some(loss);
+    }
+    if let Some(avg) = duration(matches, "MAX_AVG", 0.001)? {
+        config.defaults.thresholds.max_avg = Some(avg);
+    }
+    if let Some(p99) = duration(matches, "MAX_P99", 0.001)? {
+        config.defaults.thresholds.max_p99 = Some(p99);
+    }
+    if let Some(timeout) = value::<u64>(matches, "TIMEOUT")? {
+        config.defaults.timeout = Duration::from_millis(timeout);
+    }
+    if let Some(interval) = duration(matches, "INTERVAL", 1.)? {
+        config.defaults.interval = interval;
+    }
+    if let Some(size) = value::<usize>(matches, "SIZE")? {
+        config.defaults.size = size;
+    }
+    if let Some(fill) = value::<Fill>(matches, "FILL")? {
+        config.defaults.fill = fill;
+    }
+    if matches.is_present("DONT_FRAGMENT") {
+        config.defaults.dont_fragment = true;
+    }
+    if let Some(count) = value::<usize>(matches, "WINDOW_COUNT")? {
+        config.window.count = count;
+    }
+    if let Some(time) = value::<u64>(matches, "WINDOW_TIME")? {
+        config.window.duration = Duration::from_secs(time);
+    }
+    if let Some(interval) = duration(matches, "RESOLVE_INTERVAL", 1.)? {
+        config.resolver.interval = interval;
+    }
+    if matches.is_present("DNS_PROBE") {
+        config.dns.enabled = true;
+    }
+    if let Some(server) = value::<SocketAddr>(matches, "DNS_SERVER")? {
+        config.dns.server = Some(server);
+    }
+    if let Some(interval) = duration(matches, "DNS_INTERVAL", 1.)? {
+        config.dns.interval = interval;
+    }
+    if let Some(prometheus) = matches.value_of("PROMETHEUS") {
+        config.prometheus = Some(prometheus.to_string());
+        if matches.value_of("METRICS").is_none() {
+            config.metrics.backend = Backend::None;
+        }
+    }
+    if let Some(backend) = value::<Backend>(matches, "METRICS")? {
+        config.metrics.backend = backend;
+    }
+    if let Some(host) = matches.value_of("METRICS_HOST") {
+        config.metrics.host = host.to_string();
+    }
+    if let Some(port) = value::<u16>(matches, "METRICS_PORT")? {
+        config.metrics.port = Some(port);
+    }
+    if let Some(prefix) = matches.value_of("METRICS_PREFIX") {
+        config.metrics.prefix = prefix.to_string();
+    }
+    if matches.is_present("GRAPHITE_PICKLE") {
+        config.metrics.pickle = true;
+    }
+    if let Some(url) = matches.value_of("INFLUX") {
+        config.influx.url = Some(url.to_string());
+    }
+    if let Some(measurement) = matches.value_of("INFLUX_MEASUREMENT") {
+        config.influx.measurement = measurement.to_string();
+    }
+    if let Some(format) = value::<Format>(matches, "FORMAT")? {
+        config.output.format = format;
+    }
+    if let Some(file) = matches.value_of("OUTPUT") {
+        config.output.file = Some(file.to_string());
+    }
+
+    if let Some(files) = matches.values_of("FILE") {
+        config.files.extend(files.map(String::from));
+    }
+    if let Some(max) = value::<usize>(matches, "MAX_RANGE")? {
+        config.max_range = max;
+    }
+
+    // failed lookups would be retried as fast as they fail
+    if config.resolver.retry == Duration::from_secs(0) {
+        return Err("The resolver's retry must be more than 0".into());
+    }
+
+    let mut targets = config.targets();
+    for file in config.files.iter() {
+        targets.extend(hosts::read(file, &config.defaults)?);
+    }
+    if let Some(hosts) = matches.values_of("HOST") {
+        targets.extend(hosts.map(|host| Target::new(host, &config.defaults)));
+    }
+    let targets = hosts::expand(targets, config.max_range)?;
+    if let Some(path) = matches.value_of("KEY_FILE") {
+        config.key_file = Some(path.to_string());
+    }
+
+    // replies are matched by what's in the payload
+    let size = Payload::size(config.key.is_some() || config.key_file.is_some());
+    if let Some(target) = targets.iter().find(|t| t.size < size) {
+        return Err(format!("Size of {} is {} bytes, it must be at least {}", target.host, target.size, size).into());
+    }
+    if let Some(target) = targets.iter().find(|t| t.interval < MIN_INTERVAL) {
+        return Err(format!("Interval of {} is {:?}, it must be at least {:?}", target.host, target.interval, MIN_INTERVAL).into());
+    }
+    // IPv4 packets must fit in 64k with the IP header, IPv6 ones only count the payload
+    for target in &targets {
+        let max = match target.family {
+            Family::Ipv6 => IcmpV6::MAX_PAYLOAD,
+            _ => IcmpV4::MAX_PAYLOAD,
+        };
+        if target.size > max {
+            return Err(format!("Size of {} is {} bytes, it must be at most {}", target.host, target.size, max).into());
+        }
+    }
+
+    Ok(Options {
+        config: matches.value_of("CONFIG").map(String::from),
+        targets,
+        window: config.window,
+        prometheus: config.prometheus,
+        metrics: config.metrics,
+        influx: config.influx,
+        output: config.output,
+        resolver: config.resolver,
+        dns: config.dns,
+        count: value::<u64>(matches, "COUNT")?,
+        deadline: duration(matches, "DEADLINE", 1.)?,
+        thresholds: config.thresholds,
+        verdict: matches.value_of("VERDICT").map(String::from),
+        user: matches.value_of("USER").map(String::from).or(config.user),
+        group: matches.value_of("GROUP").map(String::from).or(config.group),
+        key: config.key,
+        key_file: config.key_file,
+    })
 }
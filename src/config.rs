@@ -0,0 +1,553 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fs;
+use std::str::FromStr;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::health::Thresholds;
+use crate::output::Format;
+use crate::pinger::SelectVersion;
+use crate::stats::{MetricsConfig, Window};
+
+/// Deserialize a duration given in (possibly fractional) seconds
+pub mod seconds {
+    use serde::{Deserialize, Deserializer};
+    use serde::de::Error;
+    use std::time::Duration;
+
+    /// A duration from seconds, unless it's negative, NaN, infinite or too
+    /// big for a Duration, which `Duration::from_secs_f64` panics on
+    pub fn from_f64(s: f64) -> Result<Duration, String> {
+        if s.is_finite() && s >= 0. && s < u64::MAX as f64 {
+            Ok(Duration::from_secs_f64(s))
+        } else {
+            Err(format!("invalid duration: {}", s))
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        from_f64(f64::deserialize(d)?).map_err(D::Error::custom)
+    }
+
+    pub mod option {
+        use serde::{Deserialize, Deserializer};
+        use std::time::Duration;
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+            #[derive(Deserialize)]
+            struct Wrap(#[serde(deserialize_with = "super::deserialize")] Duration);
+            Ok(Option::<Wrap>::deserialize(d)?.map(|Wrap(d)| d))
+        }
+    }
+}
+
+/// Shortest interval between requests to an address, so a typo in the
+/// config can't flood it
+pub const MIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Which address families to ping
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Family {
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+/// The name of an address's family, as used in metric names and labels
+pub fn family(addr: &IpAddr) -> &'static str {
+    match addr {
+        IpAddr::V4(_) => "ipv4",
+        IpAddr::V6(_) => "ipv6",
+    }
+}
+
+impl Family {
+    pub fn versions(&self) -> Vec<SelectVersion> {
+        match self {
+            Family::Any => vec![],
+            Family::Ipv4 => vec![SelectVersion::V4],
+            Family::Ipv6 => vec![SelectVersion::V6],
+        }
+    }
+}
+
+/// Which of a host's addresses to ping
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Select {
+    All,
+    /// The first address, in the order the resolver prefers
+    First,
+    /// The first address of each family
+    PerFamily,
+    /// Up to this many addresses, chosen at random but kept while they stay in DNS
+    Random(usize),
+}
+
+impl FromStr for Select {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Select::All),
+            "first" => Ok(Select::First),
+            "per-family" => Ok(Select::PerFamily),
+            "random" => Ok(Select::Random(1)),
+            _ if s.starts_with("random:") => match s["random:".len()..].parse() {
+                Ok(n) if n > 0 => Ok(Select::Random(n)),
+                _ => Err(format!("invalid count in address selection: {}", s))
+            }
+            _ => Err(format!("unknown address selection: {}, expected all, first, per-family or random:N", s))
+        }
+    }
+}
+
+impl TryFrom<String> for Select {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// What fills the payload after its timestamp
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Fill {
+    Zeros,
+    /// Different bytes in each request, so compression on the path doesn't hide size issues
+    Random,
+    /// Repeated bytes, like `ping -p`
+    Pattern(Vec<u8>),
+}
+
+impl Fill {
+    /// Fill buffer for a request, random fills are seeded from its nonce and sequence
+    pub fn fill(&self, buffer: &mut [u8], seed: u64) {
+        match self {
+            Fill::Zeros => buffer.iter_mut().for_each(|b| *b = 0),
+            Fill::Pattern(pattern) => {
+                for (b, p) in buffer.iter_mut().zip(pattern.iter().cycle()) {
+                    *b = *p;
+                }
+            }
+            Fill::Random => {
+                // splitmix64
+                let mut state = seed;
+                for chunk in buffer.chunks_mut(8) {
+                    state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                    let mut z = state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                    z ^= z >> 31;
+                    chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Fill {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zeros" => Ok(Fill::Zeros),
+            "random" => Ok(Fill::Random),
+            _ if !s.is_empty() && s.len() <= 32 => s.as_bytes().chunks(2)
+                .map(|pair| std::str::from_utf8(pair).ok()
+                    .filter(|pair| pair.len() == 2)
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+                .collect::<Option<Vec<_>>>()
+                .map(Fill::Pattern)
+                .ok_or_else(|| format!("invalid fill pattern: {}, expected pairs of hex digits", s)),
+            _ => Err(format!("unknown fill: {}, expected zeros, random or up to 16 bytes of hex", s))
+        }
+    }
+}
+
+impl TryFrom<String> for Fill {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Settings for targets that don't override them
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Defaults {
+    #[serde(with = "seconds")]
+    pub interval: Duration,
+    #[serde(with = "seconds")]
+    pub timeout: Duration,
+    /// Bytes of payload after the ICMP header, like `ping -s`
+    pub size: usize,
+    pub fill: Fill,
+    /// Set the Don't Fragment bit, so packets too big for the path are lost instead of fragmented
+    pub dont_fragment: bool,
+    pub family: Family,
+    pub select: Select,
+    pub thresholds: Thresholds,
+}
+
+impl Default for Defaults {
+    fn default() -> Self {
+        Defaults {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(1),
+            size: 56,
+            fill: Fill::Zeros,
+            dont_fragment: false,
+            family: Family::Any,
+            select: Select::All,
+            thresholds: Thresholds::default(),
+        }
+    }
+}
+
+/// A target as written in the config file, unset fields come from `Defaults`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetConfig {
+    pub host: String,
+    #[serde(default, with = "seconds::option")]
+    pub interval: Option<Duration>,
+    #[serde(default, with = "seconds::option")]
+    pub timeout: Option<Duration>,
+    pub size: Option<usize>,
+    pub fill: Option<Fill>,
+    pub dont_fragment: Option<bool>,
+    pub family: Option<Family>,
+    pub select: Option<Select>,
+    /// Unset thresholds come from the defaults
+    #[serde(default)]
+    pub thresholds: Thresholds,
+    /// Metric name, instead of the host name
+    pub name: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// A target with all of its settings resolved
+#[derive(Clone, Debug, PartialEq)]
+pub struct Target {
+    pub host: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub size: usize,
+    pub fill: Fill,
+    pub dont_fragment: bool,
+    pub family: Family,
+    pub select: Select,
+    pub thresholds: Thresholds,
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+}
+
+impl Target {
+    pub fn new(host: &str, defaults: &Defaults) -> Self {
+        Target {
+            host: host.to_string(),
+            interval: defaults.interval,
+            timeout: defaults.timeout,
+            size: defaults.size,
+            fill: defaults.fill.clone(),
+            dont_fragment: defaults.dont_fragment,
+            family: defaults.family,
+            select: defaults.select,
+            thresholds: defaults.thresholds,
+            name: host.to_string(),
+            labels: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InfluxConfig {
+    pub url: Option<String>,
+    pub measurement: String,
+}
+
+impl Default for InfluxConfig {
+    fn default() -> Self {
+        InfluxConfig {
+            url: None,
+            measurement: "ping".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResolverConfig {
+    /// How often to look hosts up again, zero to only look them up at startup and reload
+    #[serde(with = "seconds")]
+    pub interval: Duration,
+    /// Wait before retrying a failed lookup, doubling with each failure
+    #[serde(with = "seconds")]
+    pub retry: Duration,
+    /// Longest wait between retries
+    #[serde(with = "seconds")]
+    pub max_retry: Duration,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        ResolverConfig {
+            interval: Duration::from_secs(300),
+            retry: Duration::from_secs(1),
+            max_retry: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Settings for probing DNS itself, as well as pinging
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsConfig {
+    pub enabled: bool,
+    /// Nameserver to query, the first in /etc/resolv.conf if unset
+    pub server: Option<SocketAddr>,
+    #[serde(with = "seconds")]
+    pub interval: Duration,
+    #[serde(with = "seconds")]
+    pub timeout: Duration,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        DnsConfig {
+            enabled: false,
+            server: None,
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    pub format: Format,
+    /// Append to this file instead of stdout
+    pub file: Option<String>,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            format: Format::Text,
+            file: None,
+        }
+    }
+}
+
+/// Contents of the `--config` file
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub defaults: Defaults,
+    pub targets: Vec<TargetConfig>,
+    /// Files of more targets, see `hosts::read`
+    pub files: Vec<String>,
+    /// Most addresses the CIDR range targets may expand to, in all
+    pub max_range: usize,
+    pub metrics: MetricsConfig,
+    /// Address to serve Prometheus metrics on
+    pub prometheus: Option<String>,
+    pub influx: InfluxConfig,
+    pub output: OutputConfig,
+    pub window: Window,
+    pub resolver: ResolverConfig,
+    pub dns: DnsConfig,
+    /// Limits on all the targets together
+    pub thresholds: Thresholds,
+    /// User to run as once the sockets are open
+    pub user: Option<String>,
+    /// Group to run as, the user's own if unset
+    pub group: Option<String>,
+    /// Secret to sign payloads with, so forged replies are rejected
+    pub key: Option<String>,
+    /// File holding the secret, instead of key
+    pub key_file: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            defaults: Defaults::default(),
+            targets: vec![],
+            files: vec![],
+            max_range: 1024,
+            metrics: MetricsConfig::default(),
+            prometheus: None,
+            influx: InfluxConfig::default(),
+            output: OutputConfig::default(),
+            window: Window::default(),
+            resolver: ResolverConfig::default(),
+            dns: DnsConfig::default(),
+            thresholds: Thresholds::default(),
+            user: None,
+            group: None,
+            key: None,
+            key_file: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, Box<dyn Error>> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        Self::parse(&text).map_err(|e| format!("Invalid config {}: {}", path, e).into())
+    }
+
+    pub fn parse(text: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// Targets from the config file, with defaults filled in
+    pub fn targets(&self) -> Vec<Target> {
+        self.targets.iter().map(|t| {
+            let defaults = &self.defaults;
+            Target {
+                host: t.host.clone(),
+                interval: t.interval.unwrap_or(defaults.interval),
+                timeout: t.timeout.unwrap_or(defaults.timeout),
+                size: t.size.unwrap_or(defaults.size),
+                fill: t.fill.clone().unwrap_or_else(|| defaults.fill.clone()),
+                dont_fragment: t.dont_fragment.unwrap_or(defaults.dont_fragment),
+                family: t.family.unwrap_or(defaults.family),
+                select: t.select.unwrap_or(defaults.select),
+                thresholds: t.thresholds.or(&defaults.thresholds),
+                name: t.name.clone().unwrap_or_else(|| t.host.clone()),
+                labels: t.labels.clone(),
+            }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::Backend;
+
+    #[test]
+    fn parse() {
+        let config = Config::parse(r#"
+            prometheus = "0.0.0.0:9100"
+
+            [defaults]
+            interval = 5
+            timeout = 0.5
+
+            [defaults.thresholds]
+            max_loss = 5
+            max_avg = 0.1
+
+            [thresholds]
+            max_p99 = 0.25
+
+            [metrics]
+            backend = "graphite"
+            host = "carbon"
+            prefix = "ping"
+
+            [output]
+            format = "json"
+
+            [resolver]
+            interval = 60
+
+            [dns]
+            enabled = true
+            server = "[::1]:5353"
+
+            [[targets]]
+            host = "core1.example.com"
+            interval = 0.2
+            family = "ipv6"
+            labels = { site = "yvr" }
+            thresholds = { max_loss = 20 }
+
+            [[targets]]
+            host = "remote.example.com"
+            name = "remote"
+            size = 1400
+            fill = "ff00"
+            dont_fragment = true
+            select = "random:2"
+        "#).unwrap();
+
+        assert_eq!(config.metrics.backend, Backend::Graphite);
+        assert_eq!(config.metrics.port, None);
+        assert_eq!(config.output.format, Format::Json);
+        assert_eq!(config.prometheus.as_deref(), Some("0.0.0.0:9100"));
+        assert_eq!(config.resolver.interval, Duration::from_secs(60));
+        assert!(config.dns.enabled);
+        assert_eq!(config.dns.server, Some("[::1]:5353".parse().unwrap()));
+        assert_eq!(config.dns.interval, Duration::from_secs(30));
+
+        let targets = config.targets();
+        assert_eq!(targets[0].interval, Duration::from_millis(200));
+        assert_eq!(targets[0].timeout, Duration::from_millis(500));
+        assert_eq!(targets[0].family, Family::Ipv6);
+        assert_eq!(targets[0].name, "core1.example.com");
+        assert_eq!(targets[0].labels["site"], "yvr");
+        assert_eq!(targets[1].interval, Duration::from_secs(5));
+        assert_eq!(targets[1].size, 1400);
+        assert_eq!(targets[1].fill, Fill::Pattern(vec![0xff, 0]));
+        assert!(targets[1].dont_fragment);
+        assert_eq!(targets[0].fill, Fill::Zeros);
+        assert!(!targets[0].dont_fragment);
+        assert_eq!(targets[1].name, "remote");
+        assert_eq!(targets[1].select, Select::Random(2));
+        assert_eq!(targets[0].select, Select::All);
+        assert_eq!(targets[0].thresholds.max_loss, Some(20.));
+        assert_eq!(targets[0].thresholds.max_avg, Some(Duration::from_millis(100)));
+        assert_eq!(targets[1].thresholds.max_loss, Some(5.));
+        assert_eq!(config.thresholds.max_p99, Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn unknown_fields() {
+        assert!(Config::parse("[[targets]]\nhost = \"a\"\nintervall = 1\n").is_err());
+        assert!(Config::parse("[[targets]]\nhost = \"a\"\nselect = \"random:0\"\n").is_err());
+        assert!(Config::parse("[[targets]]\nhost = \"a\"\nfill = \"abc\"\n").is_err());
+    }
+
+    #[test]
+    fn seconds() {
+        assert_eq!(seconds::from_f64(0.25), Ok(Duration::from_millis(250)));
+        assert_eq!(seconds::from_f64(0.), Ok(Duration::from_secs(0)));
+        for s in &[-1., f64::NAN, f64::INFINITY, 1e20] {
+            assert!(seconds::from_f64(*s).is_err());
+        }
+        assert!(Config::parse("[defaults]\ninterval = -1\n").is_err());
+        assert!(Config::parse("[defaults]\ntimeout = 1e20\n").is_err());
+    }
+
+    #[test]
+    fn fill() {
+        let mut buffer = [1u8; 5];
+        "a5b6".parse::<Fill>().unwrap().fill(&mut buffer, 0);
+        assert_eq!(buffer, [0xa5, 0xb6, 0xa5, 0xb6, 0xa5]);
+        Fill::Zeros.fill(&mut buffer, 0);
+        assert_eq!(buffer, [0; 5]);
+
+        let mut other = [0u8; 5];
+        Fill::Random.fill(&mut buffer, 1);
+        Fill::Random.fill(&mut other, 2);
+        assert_ne!(buffer, other);
+        assert!("zz".parse::<Fill>().is_err());
+        assert!("".parse::<Fill>().is_err());
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use log::*;
+
+use crate::pinger::UniPacket;
+
+// How long answered and expired requests are remembered, so that duplicate
+// and late replies can still be attributed to their request
+const HISTORY: Duration = Duration::from_secs(60);
+
+// How long a reply is kept waiting for its request. The receiving thread can
+// pass on a fast reply, like one from loopback, before the sending thread has
+// passed on the request.
+const EARLY: Duration = Duration::from_secs(1);
+
+/// The result of matching replies against outstanding echo requests
+#[derive(PartialEq, Debug)]
+pub enum Event {
+    /// First reply to an outstanding request
+    Reply {
+        host: String,
+        addr: IpAddr,
+        seq: u16,
+        ident: u16,
+        ttl: u8,
+        size: usize,
+        rtt: Duration,
+    },
+    /// No reply arrived within the timeout
+    Timeout {
+        host: String,
+        addr: IpAddr,
+        seq: u16,
+        ident: u16,
+    },
+    /// Another reply to a request that was already answered
+    Duplicate {
+        host: String,
+        addr: IpAddr,
+        seq: u16,
+        ident: u16,
+        ttl: u8,
+        size: usize,
+        rtt: Duration,
+    },
+    /// Reply to a request that had already timed out
+    Late {
+        host: String,
+        addr: IpAddr,
+        seq: u16,
+        ident: u16,
+        ttl: u8,
+        size: usize,
+        rtt: Duration,
+    },
+}
+
+impl Event {
+    pub fn host(&self) -> &str {
+        match self {
+            Event::Reply { host, .. } | Event::Timeout { host, .. } |
+            Event::Duplicate { host, .. } | Event::Late { host, .. } => host
+        }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        match self {
+            Event::Reply { addr, .. } | Event::Timeout { addr, .. } |
+            Event::Duplicate { addr, .. } | Event::Late { addr, .. } => *addr
+        }
+    }
+}
+
+struct Probe {
+    host: String,
+    addr: IpAddr,
+    t: u128,
+    timeout: u128,
+}
+
+// A reply seen before its request
+struct Early {
+    t: u128,
+    sent: u128,
+    ttl: u8,
+    size: usize,
+}
+
+/// Matches `UniPacket::SendPacket` and `UniPacket::RecvPacket` pairs by
+/// (ident, seq) and turns them into `Event`s.
+///
+/// All times are nanoseconds since `PingTargets::start_instant`, so the
+/// sender and receiver must share the same start instant.
+#[derive(Default)]
+pub struct Correlator {
+    outstanding: HashMap<(u16, u16), Probe>,
+    answered: HashMap<(u16, u16), Probe>,
+    expired: HashMap<(u16, u16), Probe>,
+    early: HashMap<(u16, u16), Early>,
+}
+
+impl Correlator {
+    pub fn new() -> Self {
+        Correlator::default()
+    }
+
+    /// Number of requests still waiting for a reply
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    /// Consume a packet, returning the event it completes, if any
+    pub fn push(&mut self, packet: UniPacket) -> Option<Event> {
+        match packet {
+            UniPacket::SendPacket { host, addr, seq, ident, t, timeout } => {
+                let key = (ident, seq);
+                // the sequence number has wrapped around, so anything we
+                // remember about the previous use of this key is stale
+                self.answered.remove(&key);
+                self.expired.remove(&key);
+                let probe = Probe { host, addr, t, timeout: timeout.as_nanos() };
+                // a reply to this request that got here first
+                if let Some(early) = self.early.remove(&key).filter(|early| early.sent == t) {
+                    let rtt = Duration::from_nanos(early.t.saturating_sub(t) as u64);
+                    let event = Event::Reply { host: probe.host.clone(), addr: probe.addr, seq, ident, ttl: early.ttl, size: early.size, rtt };
+                    self.answered.insert(key, probe);
+                    return Some(event);
+                }
+                self.outstanding.insert(key, probe)
+                    .map(|old| Event::Timeout { host: old.host, addr: old.addr, seq, ident })
+            }
+            UniPacket::RecvPacket { host, addr, seq, ident, t, sent, ttl, size } => {
+                let key = (ident, seq);
+                // the round trip from the table, checked against the send
+                // time the reply carries
+                let rtt = |probe: &Probe| {
+                    if probe.t != sent {
+                        warn!("Reply from {} ({}) icmp_seq={} carries the wrong send time, off by {} ns",
+                            probe.host, probe.addr, seq, sent as i128 - probe.t as i128);
+                    }
+                    Duration::from_nanos(t.saturating_sub(probe.t) as u64)
+                };
+                if let Some(probe) = self.outstanding.remove(&key) {
+                    let event = Event::Reply { host: probe.host.clone(), addr: probe.addr, seq, ident, ttl, size, rtt: rtt(&probe) };
+                    self.answered.insert(key, probe);
+                    Some(event)
+                } else if let Some(probe) = self.answered.get(&key) {
+                    Some(Event::Duplicate { host: probe.host.clone(), addr: probe.addr, seq, ident, ttl, size, rtt: rtt(probe) })
+                } else if let Some(probe) = self.expired.remove(&key) {
+                    let event = Event::Late { host: probe.host.clone(), addr: probe.addr, seq, ident, ttl, size, rtt: rtt(&probe) };
+                    // further replies to this request are duplicates
+                    self.answered.insert(key, probe);
+                    Some(event)
+                } else if t.saturating_sub(sent) >= HISTORY.as_nanos() {
+                    // so late the request is forgotten, only the reply's own time is left
+                    let rtt = Duration::from_nanos((t - sent) as u64);
+                    Some(Event::Late { host, addr, seq, ident, ttl, size, rtt })
+                } else {
+                    self.early.insert(key, Early { t, sent, ttl, size });
+                    None
+                }
+            }
+            UniPacket::Rejected { .. } => None
+        }
+    }
+
+    /// Time out requests sent more than their timeout before `now`
+    pub fn expire(&mut self, now: u128) -> Vec<Event> {
+        let history = HISTORY.as_nanos();
+        self.answered.retain(|_, probe| now.saturating_sub(probe.t) < history);
+        self.expired.retain(|_, probe| now.saturating_sub(probe.t) < history);
+        self.early.retain(|_, early| now.saturating_sub(early.t) < EARLY.as_nanos());
+
+        let keys: Vec<_> = self.outstanding.iter()
+            .filter(|(_, probe)| now.saturating_sub(probe.t) >= probe.timeout)
+            .map(|(&key, _)| key)
+            .collect();
+
+        let mut events = vec![];
+        for key in keys {
+            if let Some(probe) = self.outstanding.remove(&key) {
+                let (ident, seq) = key;
+                events.push(Event::Timeout { host: probe.host.clone(), addr: probe.addr, seq, ident });
+                self.expired.insert(key, probe);
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: u128 = 1_000_000;
+
+    fn send(seq: u16, t: u128, timeout: Duration) -> UniPacket {
+        UniPacket::SendPacket { host: "host".to_string(), addr: "127.0.0.1".parse().unwrap(), seq, ident: 1, t, timeout }
+    }
+
+    fn recv(seq: u16, sent: u128, t: u128) -> UniPacket {
+        UniPacket::RecvPacket { host: "host".to_string(), addr: "127.0.0.1".parse().unwrap(), seq, ident: 1, t, sent, ttl: 64, size: 64 }
+    }
+
+    #[test]
+    fn reply_and_duplicate() {
+        let mut c = Correlator::new();
+        assert_eq!(c.push(send(1, 0, Duration::from_secs(1))), None);
+        match c.push(recv(1, 0, 5 * MS)) {
+            Some(Event::Reply { rtt, .. }) => assert_eq!(rtt, Duration::from_millis(5)),
+            e => panic!("{:?}", e)
+        }
+        match c.push(recv(1, 0, 7 * MS)) {
+            Some(Event::Duplicate { rtt, .. }) => assert_eq!(rtt, Duration::from_millis(7)),
+            e => panic!("{:?}", e)
+        }
+        assert_eq!(c.outstanding(), 0);
+    }
+
+    #[test]
+    fn timeout_and_late() {
+        let mut c = Correlator::new();
+        c.push(send(1, 0, Duration::from_secs(1)));
+        assert!(c.expire(999 * MS).is_empty());
+        let events = c.expire(1000 * MS);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].host(), "host");
+        match c.push(recv(1, 0, 1500 * MS)) {
+            Some(Event::Late { rtt, .. }) => assert_eq!(rtt, Duration::from_millis(1500)),
+            e => panic!("{:?}", e)
+        }
+        assert!(matches!(c.push(recv(1, 0, 1600 * MS)), Some(Event::Duplicate { .. })));
+    }
+
+    #[test]
+    fn reply_before_request() {
+        let mut c = Correlator::new();
+        assert_eq!(c.push(recv(1, 2 * MS, 5 * MS)), None);
+        match c.push(send(1, 2 * MS, Duration::from_secs(1))) {
+            Some(Event::Reply { rtt, .. }) => assert_eq!(rtt, Duration::from_millis(3)),
+            e => panic!("{:?}", e)
+        }
+        assert_eq!(c.outstanding(), 0);
+
+        // a stray reply to some other request isn't a reply to this one
+        c.push(recv(2, MS, 5 * MS));
+        assert_eq!(c.push(send(2, 6 * MS, Duration::from_secs(1))), None);
+        c.push(recv(3, 4 * MS, 5 * MS));
+        c.expire(1005 * MS);
+        assert_eq!(c.push(send(3, 4 * MS, Duration::from_secs(1))), None);
+    }
+
+    #[test]
+    fn payload_time() {
+        let mut c = Correlator::new();
+        // the table wins over a corrupt send time
+        c.push(send(1, 0, Duration::from_secs(1)));
+        match c.push(recv(1, 3 * MS, 5 * MS)) {
+            Some(Event::Reply { rtt, .. }) => assert_eq!(rtt, Duration::from_millis(5)),
+            e => panic!("{:?}", e)
+        }
+
+        // long after the request is forgotten, the reply's own time is used
+        match c.push(recv(2, 0, 61_000 * MS)) {
+            Some(Event::Late { host, rtt, .. }) => assert_eq!((&*host, rtt), ("host", Duration::from_secs(61))),
+            e => panic!("{:?}", e)
+        }
+    }
+
+    #[test]
+    fn wraparound() {
+        let mut c = Correlator::new();
+        let timeout = Duration::from_secs(3600);
+        c.push(send(1, 0, timeout));
+        assert!(matches!(c.push(send(1, 10 * MS, timeout)), Some(Event::Timeout { .. })));
+        match c.push(recv(1, 10 * MS, 15 * MS)) {
+            Some(Event::Reply { rtt, .. }) => assert_eq!(rtt, Duration::from_millis(5)),
+            e => panic!("{:?}", e)
+        }
+    }
+}
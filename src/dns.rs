@@ -0,0 +1,529 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::iter;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+use log::*;
+
+use crate::config::{DnsConfig, Target};
+use crate::hosts;
+use crate::pinger::SelectVersion;
+
+/// The record types looked up for each target
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum RecordType {
+    A,
+    Aaaa,
+}
+
+impl RecordType {
+    fn code(&self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Aaaa => 28,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RecordType::A => "a",
+            RecordType::Aaaa => "aaaa",
+        }
+    }
+}
+
+/// How a lookup ended
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    /// The addresses in the answer, empty if the name has no records of the type
+    Answer(Vec<IpAddr>),
+    NxDomain,
+    ServFail,
+    Refused,
+    /// Any other response code
+    Rcode(u8),
+    Timeout,
+    Error(String),
+}
+
+impl Outcome {
+    pub fn status(&self) -> &'static str {
+        match self {
+            Outcome::Answer(_) => "ok",
+            Outcome::NxDomain => "nxdomain",
+            Outcome::ServFail => "servfail",
+            Outcome::Refused => "refused",
+            Outcome::Rcode(_) | Outcome::Error(_) => "error",
+            Outcome::Timeout => "timeout",
+        }
+    }
+}
+
+/// The result of one lookup by the prober
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lookup {
+    pub host: String,
+    pub record: RecordType,
+    pub server: SocketAddr,
+    pub latency: Duration,
+    pub outcome: Outcome,
+    /// The answer differs from the last successful one
+    pub changed: bool,
+}
+
+impl fmt::Display for Lookup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} from {}: {} in {:.4?}", self.host, self.record.name().to_uppercase(), self.server, self.outcome.status(), self.latency)?;
+        if self.changed {
+            write!(f, " (changed)")?;
+        }
+        Ok(())
+    }
+}
+
+fn encode_query(id: u16, name: &str, record: RecordType) -> io::Result<Vec<u8>> {
+    // header: id, recursion desired, one question
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid name: {}", name)));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&record.code().to_be_bytes());
+    query.extend_from_slice(&[0, 1]);
+    Ok(query)
+}
+
+// Offset just past the name starting at offset
+fn skip_name(packet: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(offset)? as usize;
+        match len {
+            0 => return Some(offset + 1),
+            // a compression pointer ends the name
+            l if l & 0xc0 == 0xc0 => return Some(offset + 2),
+            l => offset += l + 1
+        }
+    }
+}
+
+fn u16_at(packet: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*packet.get(offset)?, *packet.get(offset + 1)?]))
+}
+
+// The response code and the addresses of the given type in the answer section
+fn parse_response(packet: &[u8], record: RecordType) -> Option<(u8, Vec<IpAddr>)> {
+    let rcode = packet.get(3)? & 0x0f;
+    let questions = u16_at(packet, 4)?;
+    let answers = u16_at(packet, 6)?;
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = skip_name(packet, offset)? + 4;
+    }
+    let mut addrs = vec![];
+    for _ in 0..answers {
+        offset = skip_name(packet, offset)?;
+        let rtype = u16_at(packet, offset)?;
+        let length = u16_at(packet, offset + 8)? as usize;
+        let data = packet.get(offset + 10..offset + 10 + length)?;
+        offset += 10 + length;
+        // CNAMEs and anything else on the way are skipped
+        match (rtype, data.len()) {
+            (1, 4) if record == RecordType::A => {
+                let mut b = [0u8; 4];
+                b.copy_from_slice(data);
+                addrs.push(Ipv4Addr::from(b).into());
+            }
+            (28, 16) if record == RecordType::Aaaa => {
+                let mut b = [0u8; 16];
+                b.copy_from_slice(data);
+                addrs.push(Ipv6Addr::from(b).into());
+            }
+            _ => {}
+        }
+    }
+    addrs.sort();
+    Some((rcode, addrs))
+}
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+fn outcome(rcode: u8, addrs: Vec<IpAddr>) -> Outcome {
+    match rcode {
+        0 => Outcome::Answer(addrs),
+        2 => Outcome::ServFail,
+        3 => Outcome::NxDomain,
+        5 => Outcome::Refused,
+        rcode => Outcome::Rcode(rcode),
+    }
+}
+
+/// Send a single query for name to server and wait up to timeout for the answer
+pub fn query(server: SocketAddr, name: &str, record: RecordType, timeout: Duration) -> Outcome {
+    query_all(server, &[(name.to_string(), record)], timeout).remove(0).0
+}
+
+/// Send queries to server all at once, and wait up to timeout for the
+/// answers. Returns the outcome of each query, in order, with how long it took.
+pub fn query_all(server: SocketAddr, queries: &[(String, RecordType)], timeout: Duration) -> Vec<(Outcome, Duration)> {
+    let start = Instant::now();
+    let mut results = vec![None; queries.len()];
+    let socket = (|| -> io::Result<UdpSocket> {
+        let bind = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind)?;
+        socket.connect(server)?;
+        Ok(socket)
+    })();
+    let socket = match socket {
+        Ok(socket) => socket,
+        Err(e) => return queries.iter().map(|_| (Outcome::Error(e.to_string()), start.elapsed())).collect(),
+    };
+
+    // queries waiting for an answer, by id
+    let mut waiting = HashMap::new();
+    for (i, (name, record)) in queries.iter().enumerate() {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed) ^ process::id() as u16;
+        match encode_query(id, name, *record).and_then(|request| socket.send(&request)) {
+            Ok(_) => {
+                waiting.insert(id, i);
+            }
+            Err(e) => results[i] = Some((Outcome::Error(e.to_string()), start.elapsed())),
+        }
+    }
+
+    let deadline = start + timeout;
+    let mut buf = [0u8; 4096];
+    while !waiting.is_empty() {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left == Duration::from_secs(0) {
+            break;
+        }
+        let received = socket.set_read_timeout(Some(left)).and_then(|_| socket.recv(&mut buf));
+        let n = match received {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => {
+                for (_, i) in waiting.drain() {
+                    results[i] = Some((Outcome::Error(e.to_string()), start.elapsed()));
+                }
+                break;
+            }
+        };
+        // ignore anything that isn't a response to one of the queries
+        let response = &buf[..n];
+        if n < 12 || response[2] & 0x80 == 0 {
+            continue;
+        }
+        let i = match u16_at(response, 0).and_then(|id| waiting.remove(&id)) {
+            Some(i) => i,
+            None => continue
+        };
+        let outcome = match parse_response(response, queries[i].1) {
+            Some((rcode, addrs)) => outcome(rcode, addrs),
+            None => Outcome::Error("malformed response".to_string()),
+        };
+        results[i] = Some((outcome, start.elapsed()));
+    }
+    results.into_iter().map(|result| result.unwrap_or((Outcome::Timeout, timeout))).collect()
+}
+
+/// The first nameserver in /etc/resolv.conf, or the local host
+pub fn system_server() -> SocketAddr {
+    let conf = fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    conf.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("nameserver"), Some(addr)) => addr.parse::<IpAddr>().ok(),
+                _ => None
+            }
+        })
+        .next()
+        .map(|ip| SocketAddr::new(ip, 53))
+        .unwrap_or_else(|| ([127, 0, 0, 1], 53).into())
+}
+
+/// How names that aren't fully qualified are looked up, from resolv.conf
+struct Search {
+    domains: Vec<String>,
+    /// Names with at least this many dots are tried as they are first
+    ndots: usize,
+}
+
+impl Search {
+    fn system() -> Search {
+        Search::parse(&fs::read_to_string("/etc/resolv.conf").unwrap_or_default())
+    }
+
+    fn parse(conf: &str) -> Search {
+        let mut search = Search { domains: vec![], ndots: 1 };
+        for line in conf.lines() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                // the last search or domain line wins
+                Some("search") | Some("domain") => {
+                    search.domains = words.map(|d| d.trim_end_matches('.').to_string()).filter(|d| !d.is_empty()).collect();
+                }
+                Some("options") => {
+                    for option in words.filter(|o| o.starts_with("ndots:")) {
+                        if let Ok(ndots) = option["ndots:".len()..].parse::<usize>() {
+                            search.ndots = ndots.min(15);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        search
+    }
+
+    /// The names to look host up as, in the order the system resolver tries
+    /// them until one exists
+    fn names(&self, host: &str) -> Vec<String> {
+        if host.ends_with('.') {
+            return vec![host.to_string()];
+        }
+        let qualified = self.domains.iter().map(|domain| format!("{}.{}", host, domain));
+        if host.matches('.').count() >= self.ndots {
+            iter::once(host.to_string()).chain(qualified).collect()
+        } else {
+            qualified.chain(iter::once(host.to_string())).collect()
+        }
+    }
+}
+
+// Names in a hosts file, which the system resolves without asking DNS
+fn host_file_names(text: &str) -> HashSet<String> {
+    text.lines()
+        .flat_map(|line| line.split('#').next().unwrap_or("").split_whitespace().skip(1))
+        .map(|name| name.trim_end_matches('.').to_lowercase())
+        .collect()
+}
+
+/// Looks up the A and AAAA records of every target each interval, reporting
+/// the latency and outcome of each lookup. The lookups of a round are made
+/// together, so a nameserver that's down holds a round up for the timeout
+/// rather than the timeout for every host.
+pub struct Prober {
+    server: SocketAddr,
+    search: Search,
+    interval: Duration,
+    timeout: Duration,
+    targets: Mutex<Vec<Target>>,
+    // last successful answer of each lookup
+    answers: Mutex<HashMap<(String, RecordType), Vec<IpAddr>>>,
+    results: Sender<Lookup>,
+    wake: (Sender<()>, Receiver<()>),
+}
+
+impl Prober {
+    pub fn new(config: &DnsConfig, targets: Vec<Target>, results: Sender<Lookup>) -> Self {
+        let server = config.server.unwrap_or_else(system_server);
+        info!("Probing DNS through {}", server);
+        Prober {
+            server,
+            search: Search::system(),
+            interval: config.interval,
+            timeout: config.timeout,
+            targets: Mutex::new(targets),
+            answers: Mutex::new(HashMap::new()),
+            results,
+            wake: bounded(1),
+        }
+    }
+
+    /// Replace the targets, they're looked up straight away by `run`
+    pub fn set_targets(&self, targets: Vec<Target>) {
+        *self.targets.lock().unwrap() = targets;
+        let _ = self.wake.0.try_send(());
+    }
+
+    /// Look up every target once, other than addresses and names in
+    /// /etc/hosts. Short names are qualified with the search domains.
+    pub fn probe(&self) {
+        let targets = self.targets.lock().unwrap().clone();
+        let local = host_file_names(&fs::read_to_string("/etc/hosts").unwrap_or_default());
+        let mut lookups = vec![];
+        let mut seen = HashSet::new();
+        for target in targets.iter() {
+            if hosts::literal(&target.host).is_some() || local.contains(&target.host.trim_end_matches('.').to_lowercase()) {
+                continue;
+            }
+            let versions = target.family.versions();
+            let records = match &versions[..] {
+                [SelectVersion::V4] => vec![RecordType::A],
+                [SelectVersion::V6] => vec![RecordType::Aaaa],
+                _ => vec![RecordType::A, RecordType::Aaaa],
+            };
+            for record in records {
+                if seen.insert((target.host.clone(), record)) {
+                    lookups.push((target.host.clone(), record, self.search.names(&target.host)));
+                }
+            }
+        }
+
+        // try the names of every lookup in turn, until one exists
+        let mut results = vec![(Outcome::NxDomain, Duration::from_secs(0)); lookups.len()];
+        for step in 0.. {
+            let trying: Vec<usize> = (0..lookups.len())
+                .filter(|&i| results[i].0 == Outcome::NxDomain && step < lookups[i].2.len())
+                .collect();
+            if trying.is_empty() {
+                break;
+            }
+            let queries: Vec<_> = trying.iter().map(|&i| (lookups[i].2[step].clone(), lookups[i].1)).collect();
+            for (&i, (outcome, latency)) in trying.iter().zip(query_all(self.server, &queries, self.timeout)) {
+                results[i] = (outcome, results[i].1 + latency);
+            }
+        }
+
+        for ((host, record, _), (outcome, latency)) in lookups.into_iter().zip(results) {
+            let mut changed = false;
+            if let Outcome::Answer(addrs) = &outcome {
+                let mut answers = self.answers.lock().unwrap();
+                if let Some(old) = answers.insert((host.clone(), record), addrs.clone()) {
+                    changed = old != *addrs;
+                }
+            }
+            let _ = self.results.send(Lookup {
+                host,
+                record,
+                server: self.server,
+                latency,
+                outcome,
+                changed,
+            });
+        }
+        self.answers.lock().unwrap().retain(|key, _| seen.contains(key));
+    }
+
+    /// Probe each interval, and as soon as the targets change, forever
+    pub fn run(&self) {
+        loop {
+            self.probe();
+            let _ = self.wake.1.recv_timeout(self.interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Answers queries for ok.test with an address, fail.test with SERVFAIL and
+    // drops queries for slow.test, anything else is NXDOMAIN
+    fn stub() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            loop {
+                let (n, from) = socket.recv_from(&mut buf).unwrap();
+                let mut response = buf[..n].to_vec();
+                let name = String::from_utf8_lossy(&response[12..n - 4]).to_string();
+                let qtype = u16_at(&response, n - 4).unwrap();
+                response[2] |= 0x80;
+                if name.contains("slow") {
+                    continue;
+                } else if name.contains("fail") {
+                    response[3] = 2;
+                } else if name.contains("ok") {
+                    response[7] = 1;
+                    // pointer to the question name, type, class, ttl and the address
+                    response.extend_from_slice(&[0xc0, 12]);
+                    response.extend_from_slice(&qtype.to_be_bytes());
+                    response.extend_from_slice(&[0, 1, 0, 0, 0, 60]);
+                    if qtype == 1 {
+                        response.extend_from_slice(&[0, 4, 192, 0, 2, 1]);
+                    } else {
+                        response.extend_from_slice(&[0, 16, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+                    }
+                } else {
+                    response[3] = 3;
+                }
+                socket.send_to(&response, from).unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn outcomes() {
+        let server = stub();
+        let timeout = Duration::from_millis(200);
+        assert_eq!(query(server, "ok.test", RecordType::A, timeout), Outcome::Answer(vec!["192.0.2.1".parse().unwrap()]));
+        assert_eq!(query(server, "ok.test", RecordType::Aaaa, timeout), Outcome::Answer(vec!["2001:db8::1".parse().unwrap()]));
+        assert_eq!(query(server, "missing.test", RecordType::A, timeout), Outcome::NxDomain);
+        assert_eq!(query(server, "fail.test", RecordType::A, timeout), Outcome::ServFail);
+        assert_eq!(query(server, "slow.test", RecordType::A, timeout), Outcome::Timeout);
+    }
+
+    #[test]
+    fn together() {
+        let server = stub();
+        let timeout = Duration::from_millis(200);
+        let queries: Vec<_> = ["slow.test", "ok.test", "slow.test", "missing.test", "slow.test"].iter()
+            .map(|name| (name.to_string(), RecordType::A))
+            .collect();
+        let start = Instant::now();
+        let outcomes: Vec<_> = query_all(server, &queries, timeout).into_iter().map(|(outcome, _)| outcome).collect();
+        // the timeouts run at the same time
+        assert!(start.elapsed() < timeout * 2);
+        assert_eq!(outcomes, [Outcome::Timeout, Outcome::Answer(vec!["192.0.2.1".parse().unwrap()]), Outcome::Timeout, Outcome::NxDomain, Outcome::Timeout]);
+    }
+
+    #[test]
+    fn search() {
+        let search = Search::parse("# local\nnameserver 127.0.0.53\nsearch corp.example lab.example.\noptions edns0 ndots:2\n");
+        assert_eq!(search.names("www"), ["www.corp.example", "www.lab.example", "www"]);
+        assert_eq!(search.names("a.b.example"), ["a.b.example", "a.b.example.corp.example", "a.b.example.lab.example"]);
+        assert_eq!(search.names("www.example."), ["www.example."]);
+        assert_eq!(Search::parse("domain example.com\n").names("www.example.com"), ["www.example.com", "www.example.com.example.com"]);
+        assert_eq!(Search::parse("").names("localhost"), ["localhost"]);
+
+        let local = host_file_names("127.0.0.1 localhost\n::1 ip6-localhost ip6-loopback # v6\n# 10.0.0.1 commented\n");
+        assert!(local.contains("localhost") && local.contains("ip6-loopback"));
+        assert!(!local.contains("commented"));
+    }
+
+    #[test]
+    fn probe() {
+        let (results, lookups) = crossbeam_channel::unbounded();
+        let prober = Prober {
+            server: stub(),
+            search: Search::parse("search missing.test ok.test\n"),
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_millis(200),
+            targets: Mutex::new(vec![]),
+            answers: Mutex::new(HashMap::new()),
+            results,
+            wake: bounded(1),
+        };
+        let defaults = Default::default();
+        let mut target = Target::new("www", &defaults);
+        target.family = crate::config::Family::Ipv4;
+        prober.set_targets(vec![target, Target::new("localhost", &defaults), Target::new("192.0.2.1", &defaults)]);
+        prober.probe();
+
+        // www.ok.test answers after www.missing.test doesn't exist, and
+        // localhost and the address aren't looked up
+        let lookups: Vec<_> = lookups.try_iter().collect();
+        assert_eq!(lookups.len(), 1);
+        assert_eq!((&*lookups[0].host, lookups[0].record), ("www", RecordType::A));
+        assert_eq!(lookups[0].outcome, Outcome::Answer(vec!["192.0.2.1".parse().unwrap()]));
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::config::Target;
+use crate::output::json_escape;
+use crate::stats::{Statistics, Summary};
+
+/// Limits beyond which a target, or all of them together, is degraded
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Thresholds {
+    /// Percent of requests lost
+    pub max_loss: Option<f64>,
+    #[serde(with = "crate::config::seconds::option")]
+    pub max_avg: Option<Duration>,
+    #[serde(with = "crate::config::seconds::option")]
+    pub max_p99: Option<Duration>,
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.
+}
+
+impl Thresholds {
+    /// These thresholds, with any that aren't set taken from defaults
+    pub fn or(&self, defaults: &Thresholds) -> Thresholds {
+        Thresholds {
+            max_loss: self.max_loss.or(defaults.max_loss),
+            max_avg: self.max_avg.or(defaults.max_avg),
+            max_p99: self.max_p99.or(defaults.max_p99),
+        }
+    }
+
+    /// How the summary breaks the thresholds, if it does
+    pub fn check(&self, summary: &Summary) -> Vec<String> {
+        let mut reasons = vec![];
+        if let Some(max) = self.max_loss {
+            if summary.transmitted > 0 && summary.loss > max {
+                reasons.push(format!("loss {:.1}% > {}%", summary.loss, max));
+            }
+        }
+        if let (Some(max), Some(avg)) = (self.max_avg, summary.avg) {
+            if avg > max {
+                reasons.push(format!("avg {:.3} ms > {:.3} ms", ms(avg), ms(max)));
+            }
+        }
+        if let (Some(max), Some(p99)) = (self.max_p99, summary.p99) {
+            if p99 > max {
+                reasons.push(format!("p99 {:.3} ms > {:.3} ms", ms(p99), ms(max)));
+            }
+        }
+        reasons
+    }
+}
+
+/// Health of an address, a host or the whole run, from best to worst
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Up,
+    Degraded,
+    Down,
+}
+
+impl Status {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Up => "up",
+            Status::Degraded => "degraded",
+            Status::Down => "down",
+        }
+    }
+
+    /// Exit status of the process, 2 is left for config errors
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Status::Up => 0,
+            Status::Down => 1,
+            Status::Degraded => 3,
+        }
+    }
+}
+
+pub struct AddressVerdict {
+    pub addr: IpAddr,
+    pub status: Status,
+    pub reasons: Vec<String>,
+    pub summary: Summary,
+}
+
+pub struct HostVerdict {
+    pub host: String,
+    pub status: Status,
+    pub reasons: Vec<String>,
+    pub addresses: Vec<AddressVerdict>,
+}
+
+pub struct Verdict {
+    pub status: Status,
+    pub reasons: Vec<String>,
+    pub hosts: Vec<HostVerdict>,
+}
+
+fn address(addr: IpAddr, summary: Summary, thresholds: &Thresholds) -> AddressVerdict {
+    let (status, reasons) = if summary.transmitted > 0 && summary.received == 0 {
+        (Status::Down, vec!["no replies".to_string()])
+    } else {
+        let reasons = thresholds.check(&summary);
+        (if reasons.is_empty() { Status::Up } else { Status::Degraded }, reasons)
+    };
+    AddressVerdict { addr, status, reasons, summary }
+}
+
+fn host(target: &Target, statistics: &Statistics, pending: &[String], now: Option<Instant>) -> HostVerdict {
+    let addresses: Vec<_> = statistics.iter()
+        .filter(|((host, _), _)| *host == target.host)
+        .map(|((_, addr), stats)| {
+            let summary = match now {
+                Some(now) => stats.recent(now),
+                None => stats.total(),
+            };
+            address(*addr, summary, &target.thresholds)
+        })
+        .collect();
+
+    let (status, reasons) = if pending.contains(&target.host) {
+        (Status::Down, vec!["not resolved".to_string()])
+    } else if addresses.is_empty() {
+        // nothing sent yet only counts against a host at the end of a run
+        match now {
+            Some(_) => (Status::Up, vec![]),
+            None => (Status::Down, vec!["nothing sent".to_string()]),
+        }
+    } else if addresses.iter().all(|a| a.status == Status::Down) {
+        (Status::Down, vec!["no replies".to_string()])
+    } else {
+        let reasons: Vec<_> = addresses.iter()
+            .flat_map(|a| a.reasons.iter().map(move |r| format!("{} {}", a.addr, r)))
+            .collect();
+        let worst = addresses.iter().map(|a| a.status).max().unwrap_or(Status::Up);
+        (worst.min(Status::Degraded), reasons)
+    };
+    HostVerdict { host: target.host.clone(), status, reasons, addresses }
+}
+
+/// Judge the health of every target, and of all of them together, over the
+/// whole run or, given now, over the statistics window
+pub fn evaluate(targets: &[Target], overall: &Thresholds, statistics: &Statistics, pending: &[String], now: Option<Instant>) -> Verdict {
+    let mut seen = HashSet::new();
+    let hosts: Vec<_> = targets.iter()
+        .filter(|t| seen.insert(&t.host))
+        .map(|t| host(t, statistics, pending, now))
+        .collect();
+
+    let reasons = overall.check(&statistics.overall(now));
+    let status = if !hosts.is_empty() && hosts.iter().all(|h| h.status == Status::Down) {
+        Status::Down
+    } else if !reasons.is_empty() || hosts.iter().any(|h| h.status != Status::Up) {
+        Status::Degraded
+    } else {
+        Status::Up
+    };
+    Verdict { status, reasons, hosts }
+}
+
+fn reasons_json(reasons: &[String]) -> String {
+    let reasons: Vec<_> = reasons.iter().map(|r| format!("\"{}\"", json_escape(r))).collect();
+    format!("[{}]", reasons.join(","))
+}
+
+fn ms_json(d: Option<Duration>) -> String {
+    d.map(|d| format!("{:.3}", ms(d))).unwrap_or_else(|| "null".to_string())
+}
+
+impl Verdict {
+    /// The verdict as a JSON object, with the statistics behind it
+    pub fn json(&self) -> String {
+        let mut out = String::new();
+        write!(out, "{{\"status\":\"{}\",\"reasons\":{},\"hosts\":[", self.status.name(), reasons_json(&self.reasons)).unwrap();
+        for (i, host) in self.hosts.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write!(out, "{{\"host\":\"{}\",\"status\":\"{}\",\"reasons\":{},\"addresses\":[",
+                json_escape(&host.host), host.status.name(), reasons_json(&host.reasons)).unwrap();
+            for (j, a) in host.addresses.iter().enumerate() {
+                if j > 0 {
+                    out.push(',');
+                }
+                let s = &a.summary;
+                write!(out, "{{\"addr\":\"{}\",\"status\":\"{}\",\"reasons\":{},\"transmitted\":{},\"received\":{},\"loss\":{:.1},\"avg_ms\":{},\"p99_ms\":{}}}",
+                    a.addr, a.status.name(), reasons_json(&a.reasons), s.transmitted, s.received, s.loss, ms_json(s.avg), ms_json(s.p99)).unwrap();
+            }
+            out.push_str("]}");
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Defaults;
+    use crate::pinger::Event;
+    use crate::stats::Window;
+
+    fn reply(stats: &mut Statistics, host: &str, addr: &str, rtt: u64) {
+        let addr = addr.parse().unwrap();
+        stats.sent(host, addr);
+        stats.record(&Event::Reply { host: host.to_string(), addr, seq: 0, ident: 1, ttl: 64, size: 64, rtt: Duration::from_millis(rtt) });
+    }
+
+    fn timeout(stats: &mut Statistics, host: &str, addr: &str) {
+        let addr = addr.parse().unwrap();
+        stats.sent(host, addr);
+        stats.record(&Event::Timeout { host: host.to_string(), addr, seq: 0, ident: 1 });
+    }
+
+    #[test]
+    fn verdicts() {
+        let mut defaults = Defaults::default();
+        defaults.thresholds.max_avg = Some(Duration::from_millis(50));
+        let targets: Vec<_> = ["fast", "slow", "dead"].iter().map(|h| Target::new(h, &defaults)).collect();
+        let mut stats = Statistics::new(Window::default());
+
+        reply(&mut stats, "fast", "192.0.2.1", 10);
+        reply(&mut stats, "slow", "192.0.2.2", 80);
+        timeout(&mut stats, "dead", "192.0.2.3");
+
+        let verdict = evaluate(&targets[..1], &Thresholds::default(), &stats, &[], None);
+        assert_eq!(verdict.status, Status::Up);
+
+        let verdict = evaluate(&targets, &Thresholds::default(), &stats, &[], None);
+        let statuses: Vec<_> = verdict.hosts.iter().map(|h| h.status).collect();
+        assert_eq!(statuses, [Status::Up, Status::Degraded, Status::Down]);
+        assert_eq!(verdict.hosts[1].reasons, ["192.0.2.2 avg 80.000 ms > 50.000 ms"]);
+        assert_eq!(verdict.status, Status::Degraded);
+        assert_eq!(verdict.status.exit_code(), 3);
+
+        let verdict = evaluate(&targets[2..], &Thresholds::default(), &stats, &[], None);
+        assert_eq!(verdict.status, Status::Down);
+
+        let verdict = evaluate(&targets[..1], &Thresholds::default(), &stats, &["fast".to_string()], None);
+        assert_eq!(verdict.hosts[0].reasons, ["not resolved"]);
+        assert_eq!(verdict.status, Status::Down);
+
+        // overall thresholds apply to every probe together
+        let overall = Thresholds { max_loss: Some(10.), ..Thresholds::default() };
+        let verdict = evaluate(&targets, &overall, &stats, &[], None);
+        assert_eq!(verdict.reasons, ["loss 33.3% > 10%"]);
+        assert!(verdict.json().starts_with("{\"status\":\"degraded\",\"reasons\":[\"loss 33.3% > 10%\"],\"hosts\":[{\"host\":\"fast\",\"status\":\"up\",\"reasons\":[],\"addresses\":[{\"addr\":\"192.0.2.1\",\"status\":\"up\",\"reasons\":[],\"transmitted\":1,\"received\":1,\"loss\":0.0,\"avg_ms\":10.000,\"p99_ms\":10.000}]}"));
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+
+use nix::net::if_::if_nametoindex;
+
+use crate::config::{Defaults, Target};
+
+/// Parse a literal address, which may be scoped to an interface by name or
+/// index like `fe80::1%eth0`
+pub fn literal(host: &str) -> Option<SocketAddr> {
+    match host.find('%') {
+        Some(i) => {
+            let ip: Ipv6Addr = host[..i].parse().ok()?;
+            let scope = &host[i + 1..];
+            let scope_id = match scope.parse::<u32>() {
+                Ok(index) => index,
+                Err(_) => if_nametoindex(scope).ok()?,
+            };
+            Some(SocketAddrV6::new(ip, 0, 0, scope_id).into())
+        }
+        None => host.parse::<IpAddr>().ok().map(|ip| (ip, 0).into())
+    }
+}
+
+/// A CIDR range like `192.0.2.0/28`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Range {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Range {
+    pub fn parse(s: &str) -> Option<Range> {
+        let i = s.find('/')?;
+        let addr: IpAddr = s[..i].parse().ok()?;
+        let prefix: u8 = s[i + 1..].parse().ok()?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > bits {
+            return None;
+        }
+        Some(Range { addr, prefix })
+    }
+
+    fn host_bits(&self) -> u32 {
+        (if self.addr.is_ipv4() { 32 } else { 128 }) - self.prefix as u32
+    }
+
+    /// Number of addresses that `addrs` yields, saturating for huge IPv6 ranges
+    pub fn len(&self) -> u128 {
+        let size = 1u128.checked_shl(self.host_bits()).unwrap_or(u128::MAX);
+        match self.addr {
+            // skip the network and broadcast addresses, except in /31 and /32
+            IpAddr::V4(_) if self.prefix < 31 => size - 2,
+            _ => size,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The addresses in the range
+    pub fn addrs(&self) -> Vec<IpAddr> {
+        let host_bits = self.host_bits();
+        match self.addr {
+            IpAddr::V4(a) => {
+                let mask = if host_bits == 32 { 0 } else { u32::MAX << host_bits };
+                let network = u32::from(a) & mask;
+                let (first, last) = if self.prefix < 31 { (1, !mask - 1) } else { (0, !mask) };
+                (first..=last).map(|i| IpAddr::V4(Ipv4Addr::from(network + i))).collect()
+            }
+            IpAddr::V6(a) => {
+                let mask = if host_bits == 128 { 0 } else { u128::MAX << host_bits };
+                let network = u128::from(a) & mask;
+                (0..=!mask).map(|i| IpAddr::V6(Ipv6Addr::from(network + i))).collect()
+            }
+        }
+    }
+}
+
+/// Replace targets given as CIDR ranges with a target for each address,
+/// refusing ranges with more than max addresses between them
+pub fn expand(targets: Vec<Target>, max: usize) -> Result<Vec<Target>, String> {
+    let mut expanded = vec![];
+    let mut total = 0u128;
+    for target in targets {
+        let range = match Range::parse(&target.host) {
+            Some(range) => range,
+            None => {
+                expanded.push(target);
+                continue;
+            }
+        };
+        if range.len() > max as u128 {
+            return Err(format!("{} has {} addresses, more than the limit of {} (--max-range)", target.host, range.len(), max));
+        }
+        total += range.len();
+        if total > max as u128 {
+            return Err(format!("Ranges up to and including {} have {} addresses between them, more than the limit of {} (--max-range)", target.host, total, max));
+        }
+        for addr in range.addrs() {
+            let host = addr.to_string();
+            let name = if target.name == target.host { host.clone() } else { format!("{}-{}", target.name, host) };
+            expanded.push(Target { host, name, ..target.clone() });
+        }
+    }
+    Ok(expanded)
+}
+
+/// Read targets from a file of hosts, addresses or ranges, one per line,
+/// each optionally followed by labels as `key=value`. Blank lines and
+/// anything after a `#` are ignored.
+pub fn read(path: &str, defaults: &Defaults) -> Result<Vec<Target>, Box<dyn Error>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read {}: {}", path, e))?;
+    parse(&text, defaults).map_err(|e| format!("Invalid host file {}: {}", path, e).into())
+}
+
+pub fn parse(text: &str, defaults: &Defaults) -> Result<Vec<Target>, String> {
+    let mut targets = vec![];
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut words = line.split_whitespace();
+        let host = match words.next() {
+            Some(host) => host,
+            None => continue
+        };
+        let mut labels = BTreeMap::new();
+        for word in words {
+            match word.find('=') {
+                Some(i) if i > 0 => {
+                    labels.insert(word[..i].to_string(), word[i + 1..].to_string());
+                }
+                _ => return Err(format!("line {}: expected key=value, found {}", n + 1, word))
+            }
+        }
+        targets.push(Target { labels, ..Target::new(host, defaults) });
+    }
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals() {
+        assert_eq!(literal("192.0.2.1"), Some("192.0.2.1:0".parse().unwrap()));
+        assert_eq!(literal("example.com"), None);
+        match literal("fe80::1%2") {
+            Some(SocketAddr::V6(a)) => assert_eq!((a.ip().to_string(), a.scope_id()), ("fe80::1".to_string(), 2)),
+            other => panic!("{:?}", other)
+        }
+        assert!(literal("fe80::1%lo").is_some());
+        assert_eq!(literal("fe80::1%nonexistent0"), None);
+    }
+
+    #[test]
+    fn ranges() {
+        let range = Range::parse("192.0.2.9/29").unwrap();
+        assert_eq!(range.len(), 6);
+        assert_eq!(range.addrs().first(), Some(&"192.0.2.9".parse().unwrap()));
+        assert_eq!(range.addrs().last(), Some(&"192.0.2.14".parse().unwrap()));
+        assert_eq!(Range::parse("192.0.2.1/32").unwrap().addrs(), vec!["192.0.2.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(Range::parse("2001:db8::/126").unwrap().len(), 4);
+        assert_eq!(Range::parse("2001:db8::/0").unwrap().len(), u128::MAX);
+        assert_eq!(Range::parse("192.0.2.0/33"), None);
+
+        let defaults = Defaults::default();
+        let mut target = Target::new("192.0.2.0/30", &defaults);
+        target.name = "lab".to_string();
+        let targets = expand(vec![target, Target::new("example.com", &defaults)], 4).unwrap();
+        let names: Vec<_> = targets.iter().map(|t| (&*t.host, &*t.name)).collect();
+        assert_eq!(names, [("192.0.2.1", "lab-192.0.2.1"), ("192.0.2.2", "lab-192.0.2.2"), ("example.com", "example.com")]);
+        assert!(expand(vec![Target::new("10.0.0.0/8", &defaults)], 1024).is_err());
+        // the limit is on all the ranges together
+        let ranges = vec![Target::new("192.0.2.0/30", &defaults), Target::new("198.51.100.0/30", &defaults)];
+        assert_eq!(expand(ranges.clone(), 4).unwrap().len(), 4);
+        assert!(expand(ranges, 3).is_err());
+    }
+
+    #[test]
+    fn host_file() {
+        let targets = parse("# inventory\nweb1.example.com role=web dc=yvr\n\n192.0.2.0/30  # lab\n", &Defaults::default()).unwrap();
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[0].labels["role"], "web");
+        assert_eq!(targets[0].labels["dc"], "yvr");
+        assert_eq!(targets[1].host, "192.0.2.0/30");
+        assert!(parse("a.example.com web\n", &Defaults::default()).is_err());
+    }
+}
@@ -1,4 +1,9 @@
 use std::io;
+use std::mem;
+use std::fmt;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
 //use std::convert::TryInto;
 
 use std::os::unix::io::{AsRawFd, RawFd};
@@ -7,16 +12,30 @@ use socket2::{Domain, Protocol, SockAddr, Socket as Socket2, Type};
 
 use std::io::Write;
 
-// Some tokens to allow us to identify which event is for which socket.
+use hmac::{Hmac, Mac, NewMac};
+use log::*;
+use sha2::Sha256;
 
-//const ECHO_REQUEST_BUFFER_SIZE: usize = ICMP_HEADER_SIZE + TOKEN_SIZE + 32;
+pub const ICMP_HEADER_SIZE: usize = 8;
 
 #[derive(Debug)]
 pub enum PacketError {
-    InvalidSize,
+    /// The payload doesn't fit in a packet, with the size given
+    InvalidSize(usize),
     InvalidPacket,
 }
 
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PacketError::InvalidSize(size) => write!(f, "payload of {} bytes is too big for a packet", size),
+            PacketError::InvalidPacket => write!(f, "invalid packet"),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
 pub struct IcmpV4;
 pub struct IcmpV6;
 
@@ -25,6 +44,8 @@ pub trait Proto {
     const ECHO_REQUEST_CODE: u8;
     const ECHO_REPLY_TYPE: u8;
     const ECHO_REPLY_CODE: u8;
+    /// Largest payload after the ICMP header
+    const MAX_PAYLOAD: usize;
 }
 
 impl Proto for IcmpV4 {
@@ -32,6 +53,8 @@ impl Proto for IcmpV4 {
     const ECHO_REQUEST_CODE: u8 = 0;
     const ECHO_REPLY_TYPE: u8 = 0;
     const ECHO_REPLY_CODE: u8 = 0;
+    // the 16 bit total length includes the IP header
+    const MAX_PAYLOAD: usize = 65535 - 20 - ICMP_HEADER_SIZE;
 }
 
 impl Proto for IcmpV6 {
@@ -39,6 +62,133 @@ impl Proto for IcmpV6 {
     const ECHO_REQUEST_CODE: u8 = 0;
     const ECHO_REPLY_TYPE: u8 = 129;
     const ECHO_REPLY_CODE: u8 = 0;
+    // the payload length doesn't include the IPv6 header, jumbograms aren't supported
+    const MAX_PAYLOAD: usize = 65535 - ICMP_HEADER_SIZE;
+}
+
+/// The start of the payload of each request, which replies echo back. The
+/// nonce is picked at random by each process, so replies to other processes
+/// that happen to use the same ident, like another pinglogger or `ping`, are
+/// ignored. The ident is repeated for ping sockets, which replace it.
+///
+/// With a key, the payload ends with an HMAC of the rest, so replies to
+/// requests we never sent, or with a changed time or sequence, are rejected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Payload {
+    pub nonce: u64,
+    pub ident: u16,
+    pub seq: u16,
+    /// Nanoseconds since `PingTargets::start_instant`
+    pub sent: u128,
+    pub mac: Option<[u8; MAC_SIZE]>,
+}
+
+const MAGIC: [u8; 4] = *b"PLGR";
+const VERSION_PLAIN: u8 = 1;
+const VERSION_HMAC: u8 = 2;
+/// Bytes of HMAC-SHA256 kept
+pub const MAC_SIZE: usize = 16;
+
+/// Why a reply to one of our requests was rejected
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Rejection {
+    /// No HMAC, though we sign our requests
+    Unsigned,
+    /// The HMAC doesn't match
+    BadMac,
+    /// The ident or sequence in the header differs from the payload
+    Mismatch,
+}
+
+impl Rejection {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rejection::Unsigned => "unsigned",
+            Rejection::BadMac => "bad_mac",
+            Rejection::Mismatch => "mismatch",
+        }
+    }
+}
+
+impl Payload {
+    const PLAIN_SIZE: usize = 4 + 1 + 8 + 2 + 2 + 16;
+
+    pub fn new(nonce: u64, ident: u16, seq: u16, sent: u128) -> Self {
+        Payload { nonce, ident, seq, sent, mac: None }
+    }
+
+    /// Bytes needed for a payload, signed if there's a key
+    pub fn size(signed: bool) -> usize {
+        if signed { Self::PLAIN_SIZE + MAC_SIZE } else { Self::PLAIN_SIZE }
+    }
+
+    fn fields(&self, version: u8) -> Vec<u8> {
+        let mut buffer = MAGIC.to_vec();
+        buffer.push(version);
+        buffer.extend_from_slice(&self.nonce.to_be_bytes());
+        buffer.extend_from_slice(&self.ident.to_be_bytes());
+        buffer.extend_from_slice(&self.seq.to_be_bytes());
+        buffer.extend_from_slice(&self.sent.to_be_bytes());
+        buffer
+    }
+
+    fn hmac(&self, key: &[u8]) -> [u8; MAC_SIZE] {
+        let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC takes keys of any size");
+        mac.update(&self.fields(VERSION_HMAC));
+        let mut out = [0u8; MAC_SIZE];
+        out.copy_from_slice(&mac.finalize().into_bytes()[..MAC_SIZE]);
+        out
+    }
+
+    pub fn sign(self, key: &[u8]) -> Self {
+        Payload { mac: Some(self.hmac(key)), ..self }
+    }
+
+    /// Whether the payload is signed with key, in constant time
+    pub fn verify(&self, key: &[u8]) -> bool {
+        match self.mac {
+            Some(mac) => mac.iter().zip(self.hmac(key).iter()).fold(0, |d, (a, b)| d | (a ^ b)) == 0,
+            None => false
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self.mac {
+            Some(mac) => {
+                let mut buffer = self.fields(VERSION_HMAC);
+                buffer.extend_from_slice(&mac);
+                buffer
+            }
+            None => self.fields(VERSION_PLAIN)
+        }
+    }
+
+    /// The payload at the start of buffer, if it has one
+    pub fn decode(buffer: &[u8]) -> Option<Payload> {
+        if buffer.len() < Self::PLAIN_SIZE || buffer[..4] != MAGIC {
+            return None;
+        }
+        let mut nonce = [0u8; 8];
+        nonce.copy_from_slice(&buffer[5..13]);
+        let mut sent = [0u8; 16];
+        sent.copy_from_slice(&buffer[17..33]);
+        let mac = match buffer[4] {
+            VERSION_PLAIN => None,
+            VERSION_HMAC if buffer.len() >= Self::size(true) => {
+                let mut mac = [0u8; MAC_SIZE];
+                mac.copy_from_slice(&buffer[Self::PLAIN_SIZE..Self::size(true)]);
+                Some(mac)
+            }
+            _ => return None
+        };
+        Some(Payload {
+            nonce: u64::from_be_bytes(nonce),
+            ident: u16::from_be_bytes([buffer[13], buffer[14]]),
+            seq: u16::from_be_bytes([buffer[15], buffer[16]]),
+            sent: u128::from_be_bytes(sent),
+            mac,
+        })
+    }
 }
 
 pub struct EchoRequest<'a> {
@@ -68,7 +218,11 @@ fn write_checksum(buffer: &mut [u8]) {
 }
 
 impl<'a> EchoRequest<'a> {
-    pub fn encode<P: Proto>(&self, buffer: &mut [u8]) -> Result<(), PacketError> {
+    pub fn encode<P: Proto>(&self) -> Result<Vec<u8>, PacketError> {
+        if self.payload.len() > P::MAX_PAYLOAD {
+            return Err(PacketError::InvalidSize(self.payload.len()));
+        }
+        let mut buffer = vec![0u8; ICMP_HEADER_SIZE + self.payload.len()];
         buffer[0] = P::ECHO_REQUEST_TYPE;
         buffer[1] = P::ECHO_REQUEST_CODE;
 
@@ -77,25 +231,119 @@ impl<'a> EchoRequest<'a> {
         buffer[6] = (self.seq_cnt >> 8) as u8;
         buffer[7] = self.seq_cnt as u8;
 
-        if let Err(_) = (&mut buffer[8..]).write(self.payload) {
-            return Err(PacketError::InvalidSize)
-        }
+        buffer[ICMP_HEADER_SIZE..].copy_from_slice(self.payload);
 
-        write_checksum(buffer);
-        Ok(())
+        write_checksum(&mut buffer);
+        Ok(buffer)
     }
 }
 
+/// How a socket sends and receives ICMP
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// Needs root or CAP_NET_RAW. IPv4 packets are received with their IP header.
+    Raw,
+    /// Linux ping sockets, allowed for groups in net.ipv4.ping_group_range.
+    /// The kernel sets the identifier of requests to the socket's own, only
+    /// passes back replies to it, and strips the IP header.
+    Dgram,
+}
+
 pub struct Socket {
     pub socket: Socket2,
+    pub kind: Kind,
+    ipv6: bool,
+    /// Whether Don't Fragment is set, as it's switched for each target
+    dont_fragment: AtomicBool,
 }
 
 impl Socket {
-    pub fn new(domain: Domain, type_: Type, protocol: Protocol) -> io::Result<Self> {
+    pub fn new(domain: Domain, kind: Kind, protocol: Protocol) -> io::Result<Self> {
+        let type_ = match kind {
+            Kind::Raw => Type::raw(),
+            Kind::Dgram => Type::dgram(),
+        };
         let socket = Socket2::new(domain, type_, Some(protocol))?;
         socket.set_nonblocking(true)?;
 
-        Ok(Self { socket: socket })
+        let ipv6 = libc::c_int::from(domain) == libc::AF_INET6;
+        let socket = Self { socket, kind, ipv6, dont_fragment: AtomicBool::new(false) };
+        // the kernel's default for IPv4 sets it on packets that fit the path
+        socket.write_dont_fragment(false)?;
+        if let Err(e) = socket.enable_timestamps() {
+            debug!("No kernel receive timestamps, timing replies in user space: {}", e);
+        }
+        if let Err(e) = socket.enable_ttl() {
+            debug!("No TTL of received packets: {}", e);
+        }
+        Ok(socket)
+    }
+
+    // Have `recv` pass on the TTL or hop limit of each packet, as IPv6 and
+    // ping sockets don't pass up the IP header it's in
+    fn enable_ttl(&self) -> io::Result<()> {
+        if self.ipv6 {
+            self.setsockopt(libc::IPPROTO_IPV6, libc::IPV6_RECVHOPLIMIT, 1)
+        } else {
+            self.setsockopt(libc::IPPROTO_IP, libc::IP_RECVTTL, 1)
+        }
+    }
+
+    // Have the kernel stamp each packet with the time it was received, which
+    // `recv` passes on. Hardware timestamps aren't used, as they're from the
+    // network card's clock and the send time is from the system clock.
+    fn enable_timestamps(&self) -> io::Result<()> {
+        self.setsockopt(libc::SOL_SOCKET, libc::SO_TIMESTAMPNS, 1)
+    }
+
+    fn setsockopt(&self, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(self.as_raw_fd(), level, name,
+                &value as *const _ as *const libc::c_void, mem::size_of_val(&value) as libc::socklen_t)
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Set or clear Don't Fragment for the packets sent from now on, like
+    /// `ping -M do` and `ping -M dont`. Cleared, packets bigger than the path
+    /// MTU are fragmented rather than lost.
+    pub fn set_dont_fragment(&self, on: bool) -> io::Result<()> {
+        if self.dont_fragment.load(Ordering::Relaxed) == on {
+            return Ok(());
+        }
+        self.write_dont_fragment(on)?;
+        self.dont_fragment.store(on, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn write_dont_fragment(&self, on: bool) -> io::Result<()> {
+        if self.ipv6 {
+            self.setsockopt(libc::IPPROTO_IPV6, libc::IPV6_DONTFRAG, on as libc::c_int)
+        } else {
+            let mode = if on { libc::IP_PMTUDISC_DO } else { libc::IP_PMTUDISC_DONT };
+            self.setsockopt(libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, mode)
+        }
+    }
+
+    /// A raw socket if we're allowed one, otherwise a ping socket
+    pub fn open(domain: Domain, protocol: Protocol) -> io::Result<Self> {
+        match Self::new(domain, Kind::Raw, protocol) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                debug!("No raw socket ({}), trying a ping socket", e);
+                let socket = Self::new(domain, Kind::Dgram, protocol).map_err(|e| {
+                    let range = std::fs::read_to_string("/proc/sys/net/ipv4/ping_group_range").unwrap_or_default();
+                    io::Error::new(e.kind(), format!(
+                        "{}; raw sockets need root or CAP_NET_RAW, and ping sockets need a group in net.ipv4.ping_group_range (now {})",
+                        e, range.split_whitespace().collect::<Vec<_>>().join(" ")))
+                })?;
+                info!("Using unprivileged ping sockets");
+                Ok(socket)
+            }
+            result => result
+        }
     }
 
     pub fn send_to(&self, buf: &[u8], target: &SockAddr) -> io::Result<usize> {
@@ -106,8 +354,46 @@ impl Socket {
         self.socket.flush()
     }
 
-    pub fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, SockAddr)> {
-        self.socket.recv_from(buf)
+    /// Receive a packet, with the time the kernel received it if it was
+    /// timestamped, and its TTL or hop limit if the kernel gave it
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, SockAddr, Option<SystemTime>, Option<u8>)> {
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+        // u64s to align the control messages
+        let mut control = [0u64; 16];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of_val(&addr) as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let n = unsafe { libc::recvmsg(self.as_raw_fd(), &mut msg, 0) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let from = unsafe { SockAddr::from_raw_parts(&addr as *const _ as *const libc::sockaddr, msg.msg_namelen) };
+
+        let mut received = None;
+        let mut ttl = None;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                    (libc::SOL_SOCKET, libc::SCM_TIMESTAMPNS) => {
+                        let ts = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::timespec);
+                        received = Some(SystemTime::UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32));
+                    }
+                    (libc::IPPROTO_IP, libc::IP_TTL) | (libc::IPPROTO_IPV6, libc::IPV6_HOPLIMIT) => {
+                        ttl = Some(ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int) as u8);
+                    }
+                    _ => {}
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        Ok((n as usize, from, received, ttl))
     }
 }
 
@@ -116,3 +402,50 @@ impl AsRawFd for Socket {
         self.socket.as_raw_fd()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload() {
+        let payload = Payload::new(0x0123_4567_89ab_cdef, 7, 3, 1_000_000);
+        let mut buffer = payload.encode();
+        assert_eq!(buffer.len(), Payload::size(false));
+        buffer.extend_from_slice(&[0; 26]);
+        assert_eq!(Payload::decode(&buffer), Some(payload));
+        assert_eq!(Payload::decode(&buffer[..Payload::size(false) - 1]), None);
+        buffer[0] = 0;
+        assert_eq!(Payload::decode(&buffer), None);
+    }
+
+    #[test]
+    fn signed() {
+        let payload = Payload::new(42, 7, 3, 1_000_000).sign(b"secret");
+        let mut buffer = payload.encode();
+        assert_eq!(buffer.len(), Payload::size(true));
+        let decoded = Payload::decode(&buffer).unwrap();
+        assert!(decoded.verify(b"secret"));
+        assert!(!decoded.verify(b"other"));
+        assert!(!Payload::new(42, 7, 3, 1_000_000).verify(b"secret"));
+
+        // a changed send time no longer matches
+        buffer[32] ^= 1;
+        assert!(!Payload::decode(&buffer).unwrap().verify(b"secret"));
+    }
+
+    #[test]
+    fn echo_request() {
+        let payload = [0xa5; 56];
+        let request = EchoRequest { ident: 0x1234, seq_cnt: 7, payload: &payload };
+        let buffer = request.encode::<IcmpV4>().unwrap();
+        assert_eq!(buffer.len(), ICMP_HEADER_SIZE + 56);
+        assert_eq!(&buffer[4..8], &[0x12, 0x34, 0, 7]);
+        assert_eq!(&buffer[8..], &payload[..]);
+
+        let payload = vec![0; IcmpV4::MAX_PAYLOAD + 1];
+        let request = EchoRequest { ident: 0, seq_cnt: 0, payload: &payload };
+        assert!(matches!(request.encode::<IcmpV4>(), Err(PacketError::InvalidSize(_))));
+        assert!(request.encode::<IcmpV6>().is_ok());
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, SystemTime};
+
+use crate::config::{family, Target};
+use crate::dns::{Lookup, Outcome};
+use crate::icmp::Rejection;
+use crate::pinger::Event;
+use crate::resolver::DnsEvent;
+use crate::stats::Sink;
+use crate::writer::{Destination, Writer};
+
+const INFLUX_TIMEOUT: Duration = Duration::from_secs(2);
+// Keep datagrams under a typical MTU
+const UDP_PAYLOAD: usize = 1400;
+
+pub enum Transport {
+    Udp(UdpSocket),
+    /// Host, port and path (including the query) of the HTTP write API
+    Http(String, u16, String),
+}
+
+impl Transport {
+    /// Parse `udp://host:port` or `http://host:port/write?db=name`
+    pub fn parse(url: &str) -> io::Result<Transport> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", msg, url));
+
+        let (scheme, rest) = match url.find("://") {
+            Some(i) => (&url[..i], &url[i + 3..]),
+            None => return Err(invalid("missing scheme in influx url"))
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/write?db=pinglogger")
+        };
+        let (host, port) = match authority.rfind(':') {
+            Some(i) if !authority.ends_with(']') => {
+                let port = authority[i + 1..].parse::<u16>().map_err(|_| invalid("invalid port in influx url"))?;
+                (&authority[..i], Some(port))
+            }
+            _ => (authority, None)
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        match scheme {
+            "udp" => {
+                let addr = (host, port.unwrap_or(8089)).to_socket_addrs()?.next()
+                    .ok_or_else(|| invalid("unable to resolve influx host"))?;
+                let bind = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+                let socket = UdpSocket::bind(bind)?;
+                socket.connect(addr)?;
+                Ok(Transport::Udp(socket))
+            }
+            "http" => Ok(Transport::Http(host.to_string(), port.unwrap_or(8086), path.to_string())),
+            _ => Err(invalid("unsupported scheme in influx url"))
+        }
+    }
+
+    fn url(&self) -> String {
+        match self {
+            Transport::Udp(socket) => match socket.peer_addr() {
+                Ok(addr) => format!("udp://{}", addr),
+                Err(_) => "udp".to_string()
+            },
+            Transport::Http(host, port, path) => format!("http://{}:{}{}", host, port, path),
+        }
+    }
+}
+
+// POST lines to the HTTP write API
+fn post(host: &str, port: u16, path: &str, lines: &[String]) -> io::Result<()> {
+    let body: String = lines.concat();
+    let addr = (host, port).to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "unable to resolve influx host"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, INFLUX_TIMEOUT)?;
+    stream.set_read_timeout(Some(INFLUX_TIMEOUT))?;
+    stream.set_write_timeout(Some(INFLUX_TIMEOUT))?;
+    write!(stream, "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path, host, port, body.len(), body)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response.split_whitespace().nth(1).unwrap_or("");
+    if status.starts_with('2') {
+        Ok(())
+    } else {
+        let line = response.lines().next().unwrap_or("").to_string();
+        Err(io::Error::new(io::ErrorKind::InvalidData, line))
+    }
+}
+
+impl Destination<String> for Transport {
+    fn name(&self) -> String {
+        format!("influx at {}", self.url())
+    }
+
+    fn write(&mut self, lines: &[String]) -> Result<(), (usize, io::Error)> {
+        match self {
+            Transport::Udp(socket) => {
+                // lines in the datagrams sent so far
+                let mut sent = 0;
+                let mut datagram = String::new();
+                for (i, line) in lines.iter().enumerate() {
+                    if !datagram.is_empty() && datagram.len() + line.len() > UDP_PAYLOAD {
+                        socket.send(datagram.as_bytes()).map_err(|e| (sent, e))?;
+                        sent = i;
+                        datagram.clear();
+                    }
+                    datagram.push_str(line);
+                }
+                if !datagram.is_empty() {
+                    socket.send(datagram.as_bytes()).map_err(|e| (sent, e))?;
+                }
+                Ok(())
+            }
+            // a request is written in full or not at all
+            Transport::Http(host, port, path) => post(host, *port, path, lines).map_err(|e| (0, e)),
+        }
+    }
+}
+
+fn escape_key(value: &str) -> String {
+    value.replace('\\', "\\\\").replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+/// Format an event as a point in the InfluxDB line protocol, tagged by host,
+/// address, family, ident and any labels. rtt is in milliseconds.
+pub fn line(measurement: &str, event: &Event, labels: &BTreeMap<String, String>, t: Duration) -> Option<String> {
+    let (host, addr, ident, fields) = match event {
+        Event::Reply { host, addr, ident, seq, ttl, size, rtt } => {
+            (host, addr, ident, format!("status=\"reply\",seq={}i,rtt={},ttl={}i,size={}i",
+                seq, rtt.as_secs_f64() * 1000., ttl, size))
+        }
+        Event::Timeout { host, addr, ident, seq } => {
+            (host, addr, ident, format!("status=\"timeout\",seq={}i", seq))
+        }
+        _ => return None
+    };
+    // tags are sorted by key, as influx recommends
+    let mut tags: BTreeMap<&str, String> = labels.iter().map(|(k, v)| (&**k, escape_key(v))).collect();
+    tags.insert("host", escape_key(host));
+    tags.insert("address", escape_key(&addr.to_string()));
+    tags.insert("family", family(addr).to_string());
+    tags.insert("ident", ident.to_string());
+    let tags: String = tags.iter().map(|(k, v)| format!(",{}={}", escape_key(k), v)).collect();
+    Some(format!("{}{} {} {}\n", escape_key(measurement), tags, fields, t.as_nanos()))
+}
+
+/// Format a DNS event as a point in `<measurement>_dns`, tagged by host and any labels
+pub fn dns_line(measurement: &str, event: &DnsEvent, labels: &BTreeMap<String, String>, t: Duration) -> String {
+    let fields = match event {
+        DnsEvent::Changed { added, removed, .. } => {
+            let join = |addrs: &[IpAddr]| addrs.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(" ");
+            format!("status=\"changed\",added=\"{}\",removed=\"{}\"", join(added), join(removed))
+        }
+        DnsEvent::Failed { error, .. } => {
+            format!("status=\"failed\",error=\"{}\"", error.replace('\\', "\\\\").replace('"', "\\\""))
+        }
+    };
+    let mut tags: BTreeMap<&str, String> = labels.iter().map(|(k, v)| (&**k, escape_key(v))).collect();
+    tags.insert("host", escape_key(event.host()));
+    let tags: String = tags.iter().map(|(k, v)| format!(",{}={}", escape_key(k), v)).collect();
+    format!("{}_dns{} {} {}\n", escape_key(measurement), tags, fields, t.as_nanos())
+}
+
+/// Format a DNS probe as a point in `<measurement>_lookup`, tagged by host,
+/// record type, server and any labels. latency is in milliseconds.
+pub fn lookup_line(measurement: &str, lookup: &Lookup, labels: &BTreeMap<String, String>, t: Duration) -> String {
+    let mut fields = format!("status=\"{}\",changed={}", lookup.outcome.status(), lookup.changed);
+    if lookup.outcome != Outcome::Timeout {
+        fields.push_str(&format!(",latency={}", lookup.latency.as_secs_f64() * 1000.));
+    }
+    if let Outcome::Answer(addrs) = &lookup.outcome {
+        fields.push_str(&format!(",answers={}i", addrs.len()));
+    }
+    let mut tags: BTreeMap<&str, String> = labels.iter().map(|(k, v)| (&**k, escape_key(v))).collect();
+    tags.insert("host", escape_key(&lookup.host));
+    tags.insert("type", lookup.record.name().to_string());
+    tags.insert("server", escape_key(&lookup.server.to_string()));
+    let tags: String = tags.iter().map(|(k, v)| format!(",{}={}", escape_key(k), v)).collect();
+    format!("{}_lookup{} {} {}\n", escape_key(measurement), tags, fields, t.as_nanos())
+}
+
+/// Format a rejected reply as a point in `<measurement>_rejected`, tagged by
+/// reason and family
+pub fn rejected_line(measurement: &str, addr: IpAddr, reason: Rejection, t: Duration) -> String {
+    format!("{}_rejected,family={},reason={} address=\"{}\" {}\n", escape_key(measurement), family(&addr), reason.name(), addr, t.as_nanos())
+}
+
+/// Writes a point per reply and timeout to InfluxDB
+pub struct InfluxSink {
+    measurement: String,
+    labels: HashMap<String, BTreeMap<String, String>>,
+    writer: Writer<String>,
+}
+
+impl InfluxSink {
+    pub fn new(transport: Transport, measurement: &str) -> Self {
+        InfluxSink {
+            measurement: measurement.to_string(),
+            labels: HashMap::new(),
+            writer: Writer::new(transport),
+        }
+    }
+
+    fn push(&mut self, line: String) {
+        self.writer.push(line);
+    }
+}
+
+impl Sink for InfluxSink {
+    fn targets(&mut self, targets: &[Target]) {
+        self.labels = targets.iter().map(|t| (t.host.clone(), t.labels.clone())).collect();
+    }
+
+    fn record(&mut self, event: &Event) {
+        let t = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        let empty = BTreeMap::new();
+        let labels = self.labels.get(event.host()).unwrap_or(&empty);
+        if let Some(line) = line(&self.measurement, event, labels, t) {
+            self.push(line);
+        }
+    }
+
+    fn dns(&mut self, event: &DnsEvent) {
+        let t = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        let empty = BTreeMap::new();
+        let labels = self.labels.get(event.host()).unwrap_or(&empty);
+        let line = dns_line(&self.measurement, event, labels, t);
+        self.push(line);
+    }
+
+    fn lookup(&mut self, lookup: &Lookup) {
+        let t = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        let empty = BTreeMap::new();
+        let labels = self.labels.get(&lookup.host).unwrap_or(&empty);
+        let line = lookup_line(&self.measurement, lookup, labels, t);
+        self.push(line);
+    }
+
+    fn rejected(&mut self, addr: IpAddr, reason: Rejection) {
+        let t = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        let line = rejected_line(&self.measurement, addr, reason, t);
+        self.push(line);
+    }
+
+    fn flush(&mut self) {
+        self.writer.report();
+    }
+}
+
+pub fn influx(url: &str, measurement: &str) -> io::Result<InfluxSink> {
+    Ok(InfluxSink::new(Transport::parse(url)?, measurement))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn format() {
+        let event = Event::Reply { host: "a b,c".to_string(), addr: "::1".parse().unwrap(), seq: 3, ident: 7, ttl: 64, size: 64, rtt: Duration::from_micros(1500) };
+        let labels = vec![("site".to_string(), "yvr".to_string())].into_iter().collect();
+        assert_eq!(line("ping", &event, &labels, Duration::from_secs(1)).unwrap(),
+            "ping,address=::1,family=ipv6,host=a\\ b\\,c,ident=7,site=yvr status=\"reply\",seq=3i,rtt=1.5,ttl=64i,size=64i 1000000000\n");
+
+        let event = Event::Timeout { host: "localhost".to_string(), addr: "127.0.0.1".parse().unwrap(), seq: 4, ident: 7 };
+        assert_eq!(line("ping", &event, &BTreeMap::new(), Duration::from_secs(1)).unwrap(),
+            "ping,address=127.0.0.1,family=ipv4,host=localhost,ident=7 status=\"timeout\",seq=4i 1000000000\n");
+
+        let event = DnsEvent::Changed { host: "localhost".to_string(), added: vec!["::1".parse().unwrap()], removed: vec![] };
+        assert_eq!(dns_line("ping", &event, &BTreeMap::new(), Duration::from_secs(1)),
+            "ping_dns,host=localhost status=\"changed\",added=\"::1\",removed=\"\" 1000000000\n");
+    }
+
+    #[test]
+    fn http_write() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            let mut buf = [0u8; 1024];
+            while !String::from_utf8_lossy(&request).contains("status=\"timeout\"") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n").unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let mut sink = influx(&format!("http://127.0.0.1:{}/write?db=test", port), "ping").unwrap();
+        sink.record(&Event::Timeout { host: "localhost".to_string(), addr: "127.0.0.1".parse().unwrap(), seq: 4, ident: 7 });
+        // written by the writer thread once the sink is gone
+        drop(sink);
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /write?db=test HTTP/1.1\r\n"));
+        assert!(request.contains("\r\n\r\nping,address=127.0.0.1,family=ipv4,host=localhost,ident=7 status=\"timeout\",seq=4i "));
+    }
+}
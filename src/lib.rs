@@ -1,7 +1,18 @@
 pub mod icmp;
 pub mod pinger;
+pub mod correlator;
+pub mod resolver;
+pub mod dns;
+pub mod hosts;
+pub mod health;
+pub mod privileges;
 pub mod cli;
+pub mod config;
 pub mod stats;
+pub mod prometheus;
+pub mod influx;
+pub mod writer;
+pub mod output;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,208 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{LineWriter, Write};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+
+use crate::config::family;
+use crate::pinger::Event;
+
+/// How probe results are written out
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Human readable, like ping
+    Text,
+    /// One JSON object per line
+    Json,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown output format: {}", s))
+        }
+    }
+}
+
+const CSV_HEADER: &str = "timestamp,host,addr,family,seq,ident,ttl,size,rtt_ns,status";
+
+/// The fields of a probe result, flattened for serialisation
+pub struct Record<'a> {
+    /// Seconds since the unix epoch
+    pub timestamp: f64,
+    pub host: &'a str,
+    pub addr: IpAddr,
+    pub seq: u16,
+    pub ident: u16,
+    pub ttl: Option<u8>,
+    pub size: Option<usize>,
+    pub rtt: Option<Duration>,
+    pub status: &'static str,
+}
+
+impl<'a> Record<'a> {
+    pub fn new(event: &'a Event, timestamp: f64) -> Self {
+        let (seq, ident, ttl, size, rtt, status) = match event {
+            Event::Reply { seq, ident, ttl, size, rtt, .. } => (*seq, *ident, Some(*ttl), Some(*size), Some(*rtt), "reply"),
+            Event::Duplicate { seq, ident, ttl, size, rtt, .. } => (*seq, *ident, Some(*ttl), Some(*size), Some(*rtt), "duplicate"),
+            Event::Late { seq, ident, ttl, size, rtt, .. } => (*seq, *ident, Some(*ttl), Some(*size), Some(*rtt), "late"),
+            Event::Timeout { seq, ident, .. } => (*seq, *ident, None, None, None, "timeout"),
+        };
+        Record {
+            timestamp,
+            host: event.host(),
+            addr: event.addr(),
+            seq,
+            ident,
+            ttl,
+            size,
+            rtt,
+            status,
+        }
+    }
+
+    pub fn text(&self) -> String {
+        match (self.status, self.ttl, self.size, self.rtt) {
+            (status, Some(ttl), Some(size), Some(rtt)) => {
+                let suffix = match status {
+                    "duplicate" => " (DUP!)",
+                    "late" => " (LATE!)",
+                    _ => ""
+                };
+                format!("[{:.6}] {} bytes from {} ({}): icmp_seq={} ttl={} time={:.4?}{}",
+                    self.timestamp, size, self.host, self.addr, self.seq, ttl, rtt, suffix)
+            }
+            _ => format!("[{:.6}] timeout from {} ({}): icmp_seq={}", self.timestamp, self.host, self.addr, self.seq)
+        }
+    }
+
+    pub fn json(&self) -> String {
+        fn or_null<T: ToString>(value: Option<T>) -> String {
+            value.map(|v| v.to_string()).unwrap_or_else(|| "null".to_string())
+        }
+        format!("{{\"timestamp\":{:.6},\"host\":\"{}\",\"addr\":\"{}\",\"family\":\"{}\",\"seq\":{},\"ident\":{},\"ttl\":{},\"size\":{},\"rtt_ns\":{},\"status\":\"{}\"}}",
+            self.timestamp, json_escape(self.host), self.addr, family(&self.addr), self.seq, self.ident,
+            or_null(self.ttl), or_null(self.size), or_null(self.rtt.map(|d| d.as_nanos())), self.status)
+    }
+
+    pub fn csv(&self) -> String {
+        fn or_empty<T: ToString>(value: Option<T>) -> String {
+            value.map(|v| v.to_string()).unwrap_or_default()
+        }
+        format!("{:.6},{},{},{},{},{},{},{},{},{}",
+            self.timestamp, csv_escape(self.host), self.addr, family(&self.addr), self.seq, self.ident,
+            or_empty(self.ttl), or_empty(self.size), or_empty(self.rtt.map(|d| d.as_nanos())), self.status)
+    }
+}
+
+pub(crate) fn json_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out
+}
+
+fn csv_escape(value: &str) -> String {
+    if value.contains(&[',', '"', '\n'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Writes a line per probe result to stdout or a file
+pub struct Output {
+    format: Format,
+    writer: Box<dyn Write>,
+    header: bool,
+}
+
+impl Output {
+    /// Write to path, appending if it exists, or to stdout if there's no path.
+    /// The CSV header is only written to stdout and new or empty files.
+    pub fn new(format: Format, path: Option<&str>) -> io::Result<Self> {
+        let (writer, empty): (Box<dyn Write>, bool) = match path {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                let empty = file.metadata()?.len() == 0;
+                (Box::new(LineWriter::new(file)), empty)
+            }
+            None => (Box::new(io::stdout()), true)
+        };
+        Ok(Output {
+            format,
+            writer,
+            header: format == Format::Csv && empty,
+        })
+    }
+
+    pub fn write(&mut self, event: &Event) -> io::Result<()> {
+        let t = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        let record = Record::new(event, t.as_secs_f64());
+
+        if self.header {
+            writeln!(self.writer, "{}", CSV_HEADER)?;
+            self.header = false;
+        }
+        let line = match self.format {
+            Format::Text => record.text(),
+            Format::Json => record.json(),
+            Format::Csv => record.csv(),
+        };
+        writeln!(self.writer, "{}", line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        let event = Event::Reply { host: "a\"b".to_string(), addr: "::1".parse().unwrap(), seq: 3, ident: 7, ttl: 64, size: 64, rtt: Duration::from_micros(1500) };
+        let record = Record::new(&event, 1.5);
+        assert_eq!(record.json(), "{\"timestamp\":1.500000,\"host\":\"a\\\"b\",\"addr\":\"::1\",\"family\":\"ipv6\",\"seq\":3,\"ident\":7,\"ttl\":64,\"size\":64,\"rtt_ns\":1500000,\"status\":\"reply\"}");
+        assert_eq!(record.csv(), "1.500000,\"a\"\"b\",::1,ipv6,3,7,64,64,1500000,reply");
+
+        let event = Event::Timeout { host: "localhost".to_string(), addr: "127.0.0.1".parse().unwrap(), seq: 4, ident: 7 };
+        let record = Record::new(&event, 1.5);
+        assert_eq!(record.json(), "{\"timestamp\":1.500000,\"host\":\"localhost\",\"addr\":\"127.0.0.1\",\"family\":\"ipv4\",\"seq\":4,\"ident\":7,\"ttl\":null,\"size\":null,\"rtt_ns\":null,\"status\":\"timeout\"}");
+        assert_eq!(record.csv(), "1.500000,localhost,127.0.0.1,ipv4,4,7,,,,timeout");
+        assert_eq!(record.text(), "[1.500000] timeout from localhost (127.0.0.1): icmp_seq=4");
+    }
+
+    #[test]
+    fn csv_header_once() {
+        let path = std::env::temp_dir().join(format!("pinglogger-output-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        let event = Event::Timeout { host: "localhost".to_string(), addr: "127.0.0.1".parse().unwrap(), seq: 4, ident: 7 };
+        // restarted, appending to the same file
+        for _ in 0..2 {
+            Output::new(Format::Csv, Some(path)).unwrap().write(&event).unwrap();
+        }
+        let written = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let lines: Vec<_> = written.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], CSV_HEADER);
+    }
+}
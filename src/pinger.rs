@@ -1,13 +1,18 @@
 use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::fs::File;
+use std::io::{self, Read};
 use std::error::Error;
 use std::process;
 use std::collections::HashSet;
+use std::sync::{Mutex, RwLock};
 
-use pnet::packet::icmp::echo_reply;
+use pnet::packet::icmp::{echo_reply, IcmpTypes};
 use std::os::unix::io::{AsRawFd};
 
 use mio::{Events, Interest, Poll, Token};
-use socket2::{Domain, Protocol, Type};
+use socket2::{Domain, Protocol};
 use mio::unix::SourceFd;
 use pnet::packet::icmpv6::{Icmpv6Packet,Icmpv6Type};
 use pnet::packet::ipv4::Ipv4Packet;
@@ -15,25 +20,34 @@ use pnet::packet::ipv6::Ipv6Packet;
 use pnet::packet::Packet;
 use itertools::Itertools;
 
-use std::time::Instant;
-use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
 use dns_lookup::lookup_host;
 use log::*;
-use crossbeam_channel::Sender;
+use crossbeam_channel::{bounded, Receiver, Sender};
+use crate::config::{Fill, Select, Target, MIN_INTERVAL};
+use crate::icmp::{IcmpV4, IcmpV6, Kind, Payload, Rejection};
+
+pub use crate::correlator::{Correlator, Event};
 
 // Some tokens to allow us to identify which event is for which socket.
 const PING: Token = Token(2);
 const PING_V6: Token = Token(3);
 
-const TOKEN_SIZE: usize = 24;
-const ICMP_HEADER_SIZE: usize = 8;
-const ECHO_REQUEST_BUFFER_SIZE: usize = ICMP_HEADER_SIZE + TOKEN_SIZE + 32;
+/// Big enough for any reply, with its IP header
+const RECV_BUFFER_SIZE: usize = 65536;
 
 #[derive(Clone)]
 pub struct Site {
     pub host: String,
     pub ident: u16,
     pub sock_addr: SocketAddr,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Bytes of payload after the ICMP header
+    pub size: usize,
+    pub fill: Fill,
+    pub dont_fragment: bool,
 }
 
 #[derive(PartialEq, Debug)]
@@ -46,86 +60,350 @@ pub enum SelectVersion {
 pub enum UniPacket {
     SendPacket {
         host: String,
-        addr: String,
+        addr: IpAddr,
         seq: u16,
         ident: u16,
-        t: u128
+        t: u128,
+        timeout: Duration
     },
     RecvPacket {
+        /// The host the ident is for, and where the reply came from
+        host: String,
+        addr: IpAddr,
         seq: u16,
         ident: u16,
         t: u128,
+        /// When the request was sent, from the payload the reply echoed
+        sent: u128,
         ttl: u8,
         size: usize
+    },
+    /// A reply to one of our requests that failed the payload checks
+    Rejected {
+        addr: IpAddr,
+        reason: Rejection
+    }
+}
+
+struct Slot {
+    due: Instant,
+    seq: u16,
+    sent: u64,
+}
+
+impl Slot {
+    fn finished(&self, count: Option<u64>) -> bool {
+        count.map(|count| self.sent >= count).unwrap_or(false)
+    }
+}
+
+// The kernel's receive timestamp, on the system clock, as nanoseconds since
+// start_instant, given the time now on both. It's taken as how long ago the
+// packet arrived, unless the clock has jumped, or there's no timestamp.
+fn kernel_time(now: u128, system_now: SystemTime, received: Option<SystemTime>) -> u128 {
+    let ago = received
+        .and_then(|received| system_now.duration_since(received).ok())
+        .filter(|ago| *ago < Duration::from_secs(1));
+    match ago {
+        Some(ago) => now.saturating_sub(ago.as_nanos()),
+        None => now
+    }
+}
+
+// Give sites the ident of the running site with the same host and address,
+// or the next free one, dropping any listed twice. Returns the sites with how
+// many were added and removed, or an error if there are more than idents.
+fn assign_idents(running: &[Site], sites: Vec<Site>, next_ident: &mut u16) -> Result<(Vec<Site>, usize, usize), String> {
+    let idents: HashMap<_, _> = running.iter()
+        .map(|site| ((site.host.clone(), site.sock_addr), site.ident))
+        .collect();
+
+    let mut seen = HashSet::new();
+    let mut sites: Vec<Site> = sites.into_iter()
+        .filter(|site| seen.insert((site.host.clone(), site.sock_addr)))
+        .collect();
+    // with no more, there's always a free ident for each new site
+    if sites.len() > usize::from(u16::MAX) + 1 {
+        return Err(format!("{} addresses to ping, more than the {} there are idents for", sites.len(), usize::from(u16::MAX) + 1));
+    }
+
+    let mut used = HashSet::new();
+    let mut new = vec![];
+    for (i, site) in sites.iter_mut().enumerate() {
+        match idents.get(&(site.host.clone(), site.sock_addr)) {
+            Some(&ident) => {
+                site.ident = ident;
+                used.insert(ident);
+            }
+            None => new.push(i)
+        }
+    }
+
+    // idents keep counting up, so replies to removed sites aren't
+    // mistaken for replies to new ones
+    for &i in new.iter() {
+        while used.contains(next_ident) {
+            *next_ident = next_ident.wrapping_add(1);
+        }
+        sites[i].ident = *next_ident;
+        used.insert(*next_ident);
+        *next_ident = next_ident.wrapping_add(1);
     }
+    let removed = running.len() - (sites.len() - new.len());
+    Ok((sites, new.len(), removed))
+}
+
+/// Decides when to send to each site. Each site is sent to once per its own
+/// interval, at a phase that spreads sites evenly rather than in a burst, and
+/// each send is scheduled from the last one's due time so the cadence doesn't
+/// drift however long the sends take.
+#[derive(Default)]
+pub struct Scheduler {
+    slots: HashMap<u16, Slot>,
+    /// Stop sending to a site after this many
+    count: Option<u64>,
 }
 
+impl Scheduler {
+    pub fn new(count: Option<u64>) -> Self {
+        Scheduler {
+            slots: HashMap::new(),
+            count,
+        }
+    }
+
+    // Spread of the first send across the interval, by ident. Multiples of the
+    // golden ratio are evenly spread however many there are, and idents are
+    // handed out in sequence.
+    fn phase(ident: u16, interval: Duration) -> Duration {
+        let fraction = (ident as u32).wrapping_mul(0x9e37_79b9) as f64 / (1u64 << 32) as f64;
+        interval.mul_f64(fraction)
+    }
+
+    /// The sites due at now, as indexes into sites with the sequence number to
+    /// send, advancing their schedule. New sites are added, and removed ones
+    /// forgotten.
+    pub fn due(&mut self, sites: &[Site], now: Instant) -> Vec<(usize, u16)> {
+        let idents: HashSet<u16> = sites.iter().map(|site| site.ident).collect();
+        self.slots.retain(|ident, _| idents.contains(ident));
+
+        let count = self.count;
+        let mut due = vec![];
+        for (i, site) in sites.iter().enumerate() {
+            let interval = site.interval.max(MIN_INTERVAL);
+            let slot = self.slots.entry(site.ident).or_insert_with(|| Slot {
+                due: now + Self::phase(site.ident, interval),
+                seq: 0,
+                sent: 0,
+            });
+            if slot.due > now || slot.finished(count) {
+                continue;
+            }
+
+            due.push((i, slot.seq));
+            slot.seq = slot.seq.wrapping_add(1);
+            slot.sent += 1;
+            // skip any sends we've fallen too far behind to make, keeping the phase
+            let behind = now.duration_since(slot.due).as_nanos() / interval.as_nanos();
+            if behind > 0 {
+                debug!("Skipped {} sends to {} ({})", behind, site.host, site.sock_addr.ip());
+            }
+            slot.due += interval * (behind as u32 + 1);
+        }
+        due
+    }
+
+    /// When the next site is due, None if there's nothing left to send
+    pub fn next(&self) -> Option<Instant> {
+        self.slots.values().filter(|slot| !slot.finished(self.count)).map(|slot| slot.due).min()
+    }
+}
+
+/// The sockets and the set of sites to ping, shared between the sending and
+/// receiving threads. Sites can be replaced while running with `update`.
 pub struct PingTargets {
-    pub output: Vec<Site>,
-    pub ping_output: Vec<Site>,
-    pub sources: HashMap<u16,String>,
-    pub addrs: HashSet<std::net::IpAddr>,
+    pub output: RwLock<Vec<Site>>,
+    pub sources: RwLock<HashMap<u16,String>>,
+    pub addrs: RwLock<HashSet<std::net::IpAddr>>,
+    next_ident: Mutex<u16>,
+    /// Random for each process, and carried in every request's payload
+    nonce: u64,
+    /// Key to sign payloads with, if they're signed
+    key: Option<Vec<u8>>,
+    // signalled by `update` to wake whoever is in `wait`
+    changed: (Sender<()>, Receiver<()>),
     pub ping: crate::icmp::Socket,
     pub ping_v6: crate::icmp::Socket,
     pub start_instant: Instant,
 }
 
-impl Default for PingTargets {
-    fn default() -> Self {
-        PingTargets {
-            output: vec![],
-            ping_output: vec![],
-            sources: HashMap::new(),
-            addrs: HashSet::new(),
-            ping: crate::icmp::Socket::new(Domain::ipv4(), Type::raw(), Protocol::icmpv4()).unwrap(),
-            ping_v6: crate::icmp::Socket::new(Domain::ipv6(), Type::raw(), Protocol::icmpv6()).unwrap(),
+impl PingTargets {
+    /// Open the sockets, which may need privileges that are dropped later.
+    /// With a key, payloads are signed and replies must be too.
+    pub fn new(key: Option<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
+        let ping = crate::icmp::Socket::open(Domain::ipv4(), Protocol::icmpv4())
+            .map_err(|e| format!("Unable to open an ICMP socket: {}", e))?;
+        let ping_v6 = crate::icmp::Socket::open(Domain::ipv6(), Protocol::icmpv6())
+            .map_err(|e| format!("Unable to open an ICMPv6 socket: {}", e))?;
+        let mut nonce = [0u8; 8];
+        File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut nonce))
+            .map_err(|e| format!("Unable to read /dev/urandom: {}", e))?;
+        let nonce = u64::from_be_bytes(nonce);
+        Ok(PingTargets {
+            output: RwLock::new(vec![]),
+            sources: RwLock::new(HashMap::new()),
+            addrs: RwLock::new(HashSet::new()),
+            // start somewhere random, so instances started together don't
+            // count up through the same idents
+            next_ident: Mutex::new((nonce >> 48) as u16),
+            nonce,
+            key,
+            changed: bounded(1),
+            ping,
+            ping_v6,
             start_instant: Instant::now(),
-        }
+        })
     }
-}
 
-impl PingTargets {
     pub fn start(&mut self) {
         self.start_instant = Instant::now();
     }
 
     pub fn ping(&self, count: u16, s: &Sender<UniPacket>) {
-        self.output.iter().for_each(|site| self.ping_site(&site, count, s));
+        self.output.read().unwrap().iter().for_each(|site| self.ping_site(site, count, s));
+    }
+
+    /// Replace the set of sites, returning how many were added and removed.
+    /// They're left as they are if there are too many to tell apart.
+    ///
+    /// Sites with the same host and address as a running site keep its ident,
+    /// so their sequence numbers and statistics carry on, and pick up any
+    /// changed settings.
+    pub fn update(&self, sites: Vec<Site>) -> Result<(usize, usize), String> {
+        let mut output = self.output.write().unwrap();
+        let (sites, added, removed) = assign_idents(&output, sites, &mut self.next_ident.lock().unwrap())?;
+
+        *self.sources.write().unwrap() = sites.iter().map(|site| (site.ident, site.host.clone())).collect();
+        *self.addrs.write().unwrap() = sites.iter().map(|site| site.sock_addr.ip()).collect();
+        *output = sites;
+        let _ = self.changed.0.try_send(());
+        Ok((added, removed))
     }
 
+    /// Sleep for up to timeout, returning early if the sites are updated
+    pub fn wait(&self, timeout: Duration) {
+        let _ = self.changed.1.recv_timeout(timeout);
+    }
+
+    /// Send an echo request to site. The send time is taken as late as the
+    /// payload allows, as it's carried in it: RTTs still include signing the
+    /// payload, checksumming the packet and the kernel's send path, a few
+    /// microseconds for the usual sizes.
+    ///
+    /// Requests that can't be sent are still recorded, so they time out and
+    /// count as lost, like those the network drops.
     pub fn ping_site(&self, site: &Site, count: u16, s: &Sender<UniPacket>) {
-        let mut ping_buffer = [0u8; ECHO_REQUEST_BUFFER_SIZE];
-        let now: u128 = self.start_instant.elapsed().as_nanos();
         let seq = count;
+        let now = self.send(site, seq);
+        s.send(UniPacket::SendPacket { 
+            host: site.host.clone(),
+            addr: site.sock_addr.ip(),
+            seq,
+            ident: site.ident,
+            t: now,
+            timeout: site.timeout
+        }).unwrap();
+    }
 
+    // Send the request, logging why if it can't be, and return the send time
+    fn send(&self, site: &Site, seq: u16) -> u128 {
+        let target: &SocketAddr = &site.sock_addr;
+        let socket = match site.sock_addr {
+            SocketAddr::V4(_) => &self.ping,
+            SocketAddr::V6(_) => &self.ping_v6,
+        };
+        // only this thread sends, so it's set for just this packet
+        if let Err(e) = socket.set_dont_fragment(site.dont_fragment) {
+            warn!("Unable to set Don't Fragment for {} ({}): {}", site.host, target, e);
+            return self.start_instant.elapsed().as_nanos();
+        }
+
+        // the fill up to the site's size goes after the payload, and doesn't
+        // depend on the time
+        let start = Payload::size(self.key.is_some());
+        let mut data = vec![0u8; site.size.max(start)];
+        site.fill.fill(&mut data[start..], self.nonce ^ (u64::from(site.ident) << 16 | u64::from(seq)));
+
+        let now: u128 = self.start_instant.elapsed().as_nanos();
+        let mut payload = Payload::new(self.nonce, site.ident, seq, now);
+        if let Some(key) = &self.key {
+            payload = payload.sign(key);
+        }
+        data[..start].copy_from_slice(&payload.encode());
         let request = crate::icmp::EchoRequest {
             ident: site.ident,
             seq_cnt: seq,
-            payload: &now.to_be_bytes(),
+            payload: &data,
+        };
+        let packet = match site.sock_addr {
+            SocketAddr::V4(_) => request.encode::<IcmpV4>(),
+            SocketAddr::V6(_) => request.encode::<IcmpV6>(),
+        };
+        let packet = match packet {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("Unable to ping {} ({}): {}", site.host, target, e);
+                return now;
+            }
         };
+        match socket.send_to(&packet, &(*target).into()) {
+            Ok(_) => (),
+            // too big for the interface's MTU with Don't Fragment
+            Err(ref e) if e.raw_os_error() == Some(libc::EMSGSIZE) => {
+                warn!("Unable to send {} bytes to {} ({}) without fragmenting: {}", packet.len(), site.host, target, e);
+            }
+            // e.g. no route, or the interface of a scoped address is down
+            Err(e) => {
+                warn!("Unable to send to {} ({}): {}", site.host, target, e);
+            }
+        }
+        now
+    }
 
-        let target: &SocketAddr = &site.sock_addr;
+    // When a packet was received, in nanoseconds since start_instant
+    fn received_at(&self, received: Option<SystemTime>) -> u128 {
+        kernel_time(self.start_instant.elapsed().as_nanos(), SystemTime::now(), received)
+    }
 
-        match site.sock_addr {
-            SocketAddr::V4(_) => {
-                request.encode::<crate::icmp::IcmpV4>(&mut ping_buffer).unwrap();
-                self.ping.send_to(&ping_buffer[..64], &target.clone().into()).unwrap();
-            },
-            SocketAddr::V6(_) => {
-                request.encode::<crate::icmp::IcmpV6>(&mut ping_buffer).unwrap();
-                self.ping_v6.send_to(&ping_buffer[..64], &target.clone().into()).unwrap();
+    // Pass on a reply to one of our requests, or why it was rejected
+    fn reply(&self, from: IpAddr, t: u128, reply: &echo_reply::EchoReplyPacket, ttl: u8, size: usize, s: &Sender<UniPacket>) {
+        let kind = if from.is_ipv4() { self.ping.kind } else { self.ping_v6.kind };
+        match check_reply(kind, self.nonce, self.key.as_deref(), reply) {
+            Some(Ok(payload)) => {
+                let host = match self.sources.read().unwrap().get(&payload.ident) {
+                    Some(host) => host.clone(),
+                    None => return
+                };
+                s.send(UniPacket::RecvPacket { 
+                    host,
+                    addr: from,
+                    seq: reply.get_sequence_number(),
+                    ident: payload.ident,
+                    t,
+                    sent: payload.sent,
+                    ttl,
+                    size
+                }).unwrap();
+            }
+            Some(Err(reason)) => {
+                debug!("Rejected reply from {}: {}", from, reason.name());
+                s.send(UniPacket::Rejected { addr: from, reason }).unwrap();
             }
+            _ => {}
         }
-        s.send(UniPacket::SendPacket { 
-            host: site.host.clone(),
-            addr: site.sock_addr.ip().to_string(),
-            seq,
-            ident: site.ident,
-            t: now
-        }).unwrap();
     }
 
-    pub fn handle_icmpv6(&self, packet: &[u8], num: usize, s: &Sender<UniPacket>) {
+    pub fn handle_icmpv6(&self, packet: &[u8], num: usize, from: IpAddr, t: u128, ttl: Option<u8>, s: &Sender<UniPacket>) {
         if let Some(ipv6) = Ipv6Packet::new(&packet[..num]) {
             debug!("IPV6 {:?} {:02x}", ipv6, ipv6.payload().iter().format(" "));
         }
@@ -136,21 +414,9 @@ impl PingTargets {
 
                 if let Some(reply) = echo_reply::EchoReplyPacket::new(&packet[..num]) {
                     debug!("ECHO {:?} {:02x}", reply, reply.payload().iter().format(" "));
-                    match self.sources.get(&reply.get_identifier()) {
-                        Some(_) => {
-                            let now: u128 = self.start_instant.elapsed().as_nanos();
-                            let seq = reply.get_sequence_number();
-                            let ttl = 0;//reply.get_hop_limit();
-                            s.send(UniPacket::RecvPacket { 
-                                seq,
-                                ident: reply.get_identifier(),
-                                t: now,
-                                ttl,
-                                size: num
-                            }).unwrap();
-                        }
-                        _ => {}
-                    }
+                    // only from the control messages, as there's no IP header
+                    let ttl = ttl.unwrap_or(0);
+                    self.reply(from, t, &reply, ttl, num, s);
                 }
 
             } else {
@@ -161,7 +427,7 @@ impl PingTargets {
         if let Some(ipv6_packet) = Ipv6Packet::new(&packet[..num]) {
             debug!("IPV6-payload {:02x}", ipv6_packet.payload().iter().format(" "));
             debug!("IPV6 {:?}", ipv6_packet);
-            if self.addrs.contains(&ipv6_packet.get_source().into()) {
+            if self.addrs.read().unwrap().contains(&ipv6_packet.get_source().into()) {
                 if let Some(icmpv6) = Icmpv6Packet::new(&packet[..num]) {
                     if icmpv6.get_icmpv6_type() == Icmpv6Type(129) {
                     } else {
@@ -179,27 +445,25 @@ impl PingTargets {
 
     }
 
-    pub fn handle_icmpv4(&self, packet: &[u8], num: usize, s: &Sender<UniPacket>) {
-        if let Some(ipv4_packet) = Ipv4Packet::new(&packet[..num]) {
-            if let Some(reply) = echo_reply::EchoReplyPacket::new(ipv4_packet.payload()) { //&packet[..num]) {
-                match self.sources.get(&reply.get_identifier()) {
-                    Some(_) => {
-                        let size = ipv4_packet.payload().len();
-                        let seq = reply.get_sequence_number();
-                        let ttl = ipv4_packet.get_ttl();
-                        let now: u128 = self.start_instant.elapsed().as_nanos();
-                        s.send(UniPacket::RecvPacket { 
-                            seq,
-                            ident: reply.get_identifier(),
-                            t: now,
-                            ttl,
-                            size
-                        }).unwrap();
-                    }
-                    None => {}
+    pub fn handle_icmpv4(&self, packet: &[u8], num: usize, from: IpAddr, t: u128, ttl: Option<u8>, s: &Sender<UniPacket>) {
+        // raw sockets pass up the IP header, ping sockets only the ICMP message
+        let (icmp, ttl) = match self.ping.kind {
+            Kind::Raw => match Ipv4Packet::new(&packet[..num]) {
+                Some(ipv4_packet) => {
+                    let start = (ipv4_packet.get_header_length() as usize * 4).min(num);
+                    (&packet[start..num], ipv4_packet.get_ttl())
                 }
+                None => return
+            },
+            Kind::Dgram => (&packet[..num], ttl.unwrap_or(0))
+        };
+        if let Some(reply) = echo_reply::EchoReplyPacket::new(icmp) {
+            // raw sockets see requests too, like our own on loopback
+            if reply.get_icmp_type() != IcmpTypes::EchoReply {
+                return;
             }
-            }
+            self.reply(from, t, &reply, ttl, icmp.len(), s);
+        }
         }
 
         pub fn poll(&self, s: &Sender<UniPacket>) -> Result<(), Box<dyn Error>> {
@@ -208,42 +472,51 @@ impl PingTargets {
             let mut poll = Poll::new().unwrap();
             // Create storage for events.
             let mut events = Events::with_capacity(128);
+            let mut packet = vec![0u8; RECV_BUFFER_SIZE];
 
             poll.registry().register(&mut SourceFd(&self.ping.socket.as_raw_fd()), PING, Interest::READABLE)?;
             poll.registry().register(&mut SourceFd(&self.ping_v6.socket.as_raw_fd()), PING_V6, Interest::READABLE)?;
 
             // Start an event loop.
             loop {
-                // Poll Mio for events, blocking until we get an event.
-                poll.poll(&mut events, None).unwrap();
+                // Poll Mio for events, blocking until we get an event. It's
+                // interrupted by signals handled on this thread, like SIGHUP.
+                if let Err(e) = poll.poll(&mut events, None) {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(e.into());
+                }
 
                 for event in events.iter() {
                     match event.token() {
                         PING_V6 => {
+                            // until it would block, as events are edge triggered
                             loop {
-                                let mut packet = [0u8;2048]; 
-                                match self.ping_v6.recv(&mut packet) {
-                                    Ok((num, addr)) => {
-                                        debug!("Addr {:?}", addr);
-                                        self.handle_icmpv6(&packet, num, &s);
-                                    }
-                                    Err(_) => {
-                                        break;
-                                    }
+                                let (num, addr, received, ttl) = match self.ping_v6.recv(&mut packet) {
+                                    Ok(received) => received,
+                                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                                    Err(_) => break
+                                };
+                                debug!("Addr {:?}", addr);
+                                let t = self.received_at(received);
+                                if let Some(from) = addr.as_std() {
+                                    self.handle_icmpv6(&packet, num, from.ip(), t, ttl, s);
                                 }
                             }
                         }
                         PING => {
+                            // until it would block, as events are edge triggered
                             loop {
-                                let mut packet = [0u8;2048]; 
-                                match self.ping.recv(&mut packet) {
-                                    Ok((num, addr)) => {
-                                        debug!("Addr {:?}", addr);
-                                        self.handle_icmpv4(&packet, num, &s);
-                                    },
-                                    Err(_) => {
-                                        break;
-                                    }
+                                let (num, addr, received, ttl) = match self.ping.recv(&mut packet) {
+                                    Ok(received) => received,
+                                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                                    Err(_) => break
+                                };
+                                debug!("Addr {:?}", addr);
+                                let t = self.received_at(received);
+                                if let Some(from) = addr.as_std() {
+                                    self.handle_icmpv4(&packet, num, from.ip(), t, ttl, s);
                                 }
                             }
                         }
@@ -256,53 +529,243 @@ impl PingTargets {
         }
     }
 
-    pub fn generate_targets(hosts: Vec<&str>, versions: &Vec<SelectVersion>) -> std::result::Result<PingTargets, Box<dyn Error>> {
-        let mut result = PingTargets::default();
+    // The payload of a reply, or why it was rejected. None unless it echoes a
+    // request from this process. Ping sockets replace the ident of requests
+    // with their own, so then only the one in the payload is checked.
+    fn check_reply(kind: Kind, nonce: u64, key: Option<&[u8]>, reply: &echo_reply::EchoReplyPacket) -> Option<Result<Payload, Rejection>> {
+        let payload = Payload::decode(reply.payload()).filter(|payload| payload.nonce == nonce)?;
+        if let Some(key) = key {
+            if payload.mac.is_none() {
+                return Some(Err(Rejection::Unsigned));
+            }
+            if !payload.verify(key) {
+                return Some(Err(Rejection::BadMac));
+            }
+        }
+        let ident_matches = kind == Kind::Dgram || payload.ident == reply.get_identifier();
+        if !ident_matches || payload.seq != reply.get_sequence_number() {
+            return Some(Err(Rejection::Mismatch));
+        }
+        Some(Ok(payload))
+    }
+
+    /// Look up the addresses of a host, in the order the resolver prefers
+    /// and without duplicates. Literal addresses are returned as they are.
+    pub fn lookup(host: &str) -> std::io::Result<Vec<IpAddr>> {
+        if let Some(addr) = crate::hosts::literal(host) {
+            return Ok(vec![addr.ip()]);
+        }
+        let mut seen = HashSet::new();
+        Ok(lookup_host(host)?.into_iter().filter(|addr| seen.insert(*addr)).collect())
+    }
 
-        hosts.iter().map(|&host| {
-            match lookup_host(host) {
-                Ok(r) => Some( r.into_iter().map(move |x| (host, x))),
-                Err(e) => {
-                    error!("Err: {}", e);
-                    None
-                }
+    // Pick the addresses to ping from those in the target's family
+    fn select(target: &Target, addrs: Vec<IpAddr>) -> Vec<IpAddr> {
+        match target.select {
+            Select::All => addrs,
+            Select::First => addrs.into_iter().take(1).collect(),
+            Select::PerFamily => {
+                let v4 = addrs.iter().find(|a| a.is_ipv4());
+                let v6 = addrs.iter().find(|a| a.is_ipv6());
+                v4.into_iter().chain(v6).cloned().collect()
             }
-        }).filter_map(Option::Some).map(|x| {
-            debug!("x{:?}", x);
-            x
-        }).flatten().flatten().enumerate().for_each(|(i, (host, x))| {
-            debug!("y{:?} {:?} {:?}", i, host, x);
-            let sock_addr: SocketAddr = (x, 0).into();
-
-            let both = !versions.contains(&SelectVersion::V4) && !versions.contains(&SelectVersion::V6);
-
-            match sock_addr {
-                SocketAddr::V4(_) if both || versions.contains(&SelectVersion::V4) => {
-                    result.addrs.insert(x.clone());
-                    let site = Site {
-                        host: host.to_string(),
-                        ident: process::id() as u16 + i as u16,
-                        sock_addr
-                    };
-                    result.sources.insert(site.ident.clone(), site.host.to_string());
-                    result.output.push(site.clone());
-                    result.ping_output.push(site);
-                }
-                SocketAddr::V6(_) if both || versions.contains(&SelectVersion::V6) => {
-                    result.addrs.insert(x.clone());
-                    let site = Site {
-                        host: host.to_string(),
-                        ident: process::id() as u16 + i as u16,
-                        sock_addr
-                    };
-                    result.sources.insert(site.ident.clone(), site.host.to_string());
-                    result.output.push(site.clone());
-                    result.ping_output.push(site);
-                }
-                // default skip
-                _ => {}
+            Select::Random(n) => {
+                // rank by a hash that's fixed for this process, so the same
+                // addresses are picked each time the host is looked up
+                let rank = |addr: &IpAddr| {
+                    let mut hasher = DefaultHasher::new();
+                    (process::id(), &target.host, addr).hash(&mut hasher);
+                    hasher.finish()
+                };
+                let mut ranked = addrs.clone();
+                ranked.sort_by_key(rank);
+                ranked.truncate(n);
+                addrs.into_iter().filter(|a| ranked.contains(a)).collect()
+            }
+        }
+    }
+
+    /// Make a site for each address that matches the target's address family
+    /// and selection. Idents are assigned by `PingTargets::update`.
+    pub fn target_sites(target: &Target, addrs: &[IpAddr]) -> Vec<Site> {
+        let versions = target.family.versions();
+        let both = !versions.contains(&SelectVersion::V4) && !versions.contains(&SelectVersion::V6);
+
+        let addrs = addrs.iter().cloned().filter(|x| {
+            match x {
+                IpAddr::V4(_) => both || versions.contains(&SelectVersion::V4),
+                IpAddr::V6(_) => both || versions.contains(&SelectVersion::V6),
+            }
+        }).collect();
+
+        // keep the interface of a scoped literal like fe80::1%eth0
+        let scope_id = match crate::hosts::literal(&target.host) {
+            Some(SocketAddr::V6(addr)) => addr.scope_id(),
+            _ => 0
+        };
+
+        select(target, addrs).into_iter().map(|x| {
+            debug!("y{:?} {:?}", target.host, x);
+            let sock_addr = match x {
+                IpAddr::V6(ip) => SocketAddrV6::new(ip, 0, 0, scope_id).into(),
+                ip => (ip, 0).into()
+            };
+            Site {
+                host: target.host.to_string(),
+                ident: 0,
+                sock_addr,
+                interval: target.interval,
+                timeout: target.timeout,
+                size: target.size,
+                fill: target.fill.clone(),
+                dont_fragment: target.dont_fragment,
             }
-        });
-        Ok(result)
+        }).collect()
+    }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Defaults;
+
+    fn site(ident: u16, interval: Duration) -> Site {
+        Site {
+            host: "localhost".to_string(),
+            ident,
+            sock_addr: "127.0.0.1:0".parse().unwrap(),
+            interval,
+            timeout: Duration::from_secs(1),
+            size: 56,
+            fill: Fill::Zeros,
+            dont_fragment: false,
+        }
     }
 
+    #[test]
+    fn reply_checks() {
+        let check = |kind, key: Option<&[u8]>, packet: &[u8]| {
+            check_reply(kind, 42, key, &echo_reply::EchoReplyPacket::new(packet).unwrap())
+                .map(|checked| checked.map(|payload| payload.ident))
+        };
+        let mut packet = vec![0, 0, 0, 0, 0x12, 0x34, 0, 1];
+        packet.extend(Payload::new(42, 0x1234, 1, 0).encode());
+        assert_eq!(check(Kind::Raw, None, &packet), Some(Ok(0x1234)));
+        assert_eq!(check(Kind::Raw, Some(b"key"), &packet), Some(Err(Rejection::Unsigned)));
+        assert_eq!(check_reply(Kind::Raw, 43, None, &echo_reply::EchoReplyPacket::new(&packet).unwrap()), None);
+
+        // ping sockets rewrite the ident in the header
+        packet[4] = 0xab;
+        assert_eq!(check(Kind::Raw, None, &packet), Some(Err(Rejection::Mismatch)));
+        assert_eq!(check(Kind::Dgram, None, &packet), Some(Ok(0x1234)));
+        assert_eq!(check(Kind::Dgram, None, &packet[..20]), None);
+
+        let mut packet = vec![0, 0, 0, 0, 0x12, 0x34, 0, 1];
+        packet.extend(Payload::new(42, 0x1234, 1, 0).sign(b"key").encode());
+        assert_eq!(check(Kind::Raw, Some(b"key"), &packet), Some(Ok(0x1234)));
+        assert_eq!(check(Kind::Raw, Some(b"other"), &packet), Some(Err(Rejection::BadMac)));
+        packet[7] = 2;
+        assert_eq!(check(Kind::Raw, Some(b"key"), &packet), Some(Err(Rejection::Mismatch)));
+    }
+
+    #[test]
+    fn receive_times() {
+        let now = 5_000_000_000;
+        let system_now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        // arrived 150us ago
+        assert_eq!(kernel_time(now, system_now, Some(system_now - Duration::from_micros(150))), now - 150_000);
+        // no timestamp, or the clock jumped either way
+        assert_eq!(kernel_time(now, system_now, None), now);
+        assert_eq!(kernel_time(now, system_now, Some(system_now - Duration::from_secs(1))), now);
+        assert_eq!(kernel_time(now, system_now, Some(system_now + Duration::from_millis(1))), now);
+    }
+
+    #[test]
+    fn reload() {
+        let at = |host: &str, addr: &str, ident| Site { host: host.to_string(), sock_addr: addr.parse().unwrap(), ..site(ident, Duration::from_secs(1)) };
+        let running = vec![at("a", "192.0.2.1:0", 7), at("b", "192.0.2.2:0", 8), at("c", "192.0.2.3:0", 9)];
+
+        let mut kept = at("a", "192.0.2.1:0", 0);
+        kept.size = 1400;
+        // c is removed, d is added and listed twice, and 8 is in use so it's skipped
+        let sites = vec![kept, at("b", "192.0.2.2:0", 0), at("d", "192.0.2.4:0", 0), at("d", "192.0.2.4:0", 0)];
+        let mut next_ident = 8;
+        let (sites, added, removed) = assign_idents(&running, sites, &mut next_ident).unwrap();
+
+        assert_eq!((added, removed), (1, 1));
+        let idents: Vec<_> = sites.iter().map(|site| (&*site.host, site.ident)).collect();
+        assert_eq!(idents, [("a", 7), ("b", 8), ("d", 9)]);
+        assert_eq!(sites[0].size, 1400);
+        assert_eq!(next_ident, 10);
+
+        // every ident taken, and one more
+        let sites: Vec<_> = (0..=u32::from(u16::MAX) + 1)
+            .map(|i| Site { sock_addr: SocketAddr::from((std::net::Ipv4Addr::from(i), 0)), ..site(0, Duration::from_secs(1)) })
+            .collect();
+        assert!(assign_idents(&running, sites[1..].to_vec(), &mut next_ident).is_ok());
+        assert!(assign_idents(&running, sites, &mut next_ident).is_err());
+    }
+
+    #[test]
+    fn schedule() {
+        let ms = Duration::from_millis;
+        let sites = [site(1, ms(200)), site(2, ms(200)), site(3, ms(1000))];
+        let mut scheduler = Scheduler::default();
+        let start = Instant::now();
+
+        // step through two seconds a millisecond at a time
+        let mut sent: Vec<Vec<Duration>> = vec![vec![]; 3];
+        for t in 0..2000 {
+            let now = start + ms(t);
+            for (i, seq) in scheduler.due(&sites, now) {
+                assert_eq!(seq as usize, sent[i].len());
+                sent[i].push(now - start);
+            }
+        }
+        assert_eq!(sent.iter().map(|s| s.len()).collect::<Vec<_>>(), [10, 10, 2]);
+        // the two fast sites are out of step, and neither drifts
+        assert_ne!(sent[0][0], sent[1][0]);
+        for s in sent.iter() {
+            assert!(s.windows(2).all(|w| w[1] - w[0] == s[1] - s[0]));
+        }
+
+        // a stall skips the missed sends rather than catching up in a burst
+        let now = start + ms(5000);
+        assert_eq!(scheduler.due(&sites, now).len(), 3);
+        assert!(scheduler.due(&sites, now).is_empty());
+        assert!(scheduler.next().unwrap() > now);
+
+        // removed sites are forgotten
+        scheduler.due(&sites[..1], now);
+        assert_eq!(scheduler.slots.len(), 1);
+
+        // with a count, sites stop once they've had that many
+        let mut scheduler = Scheduler::new(Some(2));
+        let sent: usize = (0..2000).map(|t| scheduler.due(&sites, start + ms(t)).len()).sum();
+        assert_eq!(sent, 6);
+        assert_eq!(scheduler.next(), None);
+    }
+
+    #[test]
+    fn selection() {
+        let addrs: Vec<IpAddr> = ["2001:db8::1", "192.0.2.1", "2001:db8::2", "192.0.2.2", "192.0.2.3"]
+            .iter().map(|a| a.parse().unwrap()).collect();
+        let mut target = Target::new("example.com", &Defaults::default());
+        let picked = |target: &Target| -> Vec<String> {
+            target_sites(target, &addrs).iter().map(|s| s.sock_addr.ip().to_string()).collect()
+        };
+
+        assert_eq!(picked(&target).len(), 5);
+        target.select = Select::First;
+        assert_eq!(picked(&target), ["2001:db8::1"]);
+        target.select = Select::PerFamily;
+        assert_eq!(picked(&target), ["192.0.2.1", "2001:db8::1"]);
+        target.family = crate::config::Family::Ipv4;
+        assert_eq!(picked(&target), ["192.0.2.1"]);
+
+        target.select = Select::Random(2);
+        let random = picked(&target);
+        assert_eq!(random.len(), 2);
+        assert!(random.iter().all(|a| a.starts_with("192.0.2.")));
+        assert_eq!(picked(&target), random);
+    }
+}
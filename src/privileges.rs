@@ -0,0 +1,81 @@
+use std::error::Error;
+
+use log::*;
+use nix::unistd::{self, Gid, Group, Uid, User};
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: i32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Clear all of this thread's capabilities, like CAP_NET_RAW given to the
+/// binary with setcap. Capabilities are per thread, so this must happen
+/// before any other threads are started.
+pub fn clear_capabilities() -> Result<(), Box<dyn Error>> {
+    let mut header = CapHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    let data = [CapData::default(), CapData::default()];
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } != 0 {
+        return Err(format!("Unable to clear capabilities: {}", std::io::Error::last_os_error()).into());
+    }
+    Ok(())
+}
+
+fn user(name: &str) -> Result<User, Box<dyn Error>> {
+    let user = match name.parse::<u32>() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid))?,
+        Err(_) => User::from_name(name)?,
+    };
+    user.ok_or_else(|| format!("Unknown user {}", name).into())
+}
+
+fn group(name: &str) -> Result<Gid, Box<dyn Error>> {
+    if let Ok(gid) = name.parse::<u32>() {
+        return Ok(Gid::from_raw(gid));
+    }
+    Group::from_name(name)?
+        .map(|group| group.gid)
+        .ok_or_else(|| format!("Unknown group {}", name).into())
+}
+
+/// Once the sockets are open, switch to user and group, if given, and give
+/// up any capabilities. The group defaults to the user's own.
+pub fn drop(user_name: Option<&str>, group_name: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let user = user_name.map(user).transpose()?;
+    let gid = match group_name {
+        Some(name) => Some(group(name)?),
+        None => user.as_ref().map(|user| user.gid),
+    };
+
+    if let Some(gid) = gid {
+        // supplementary groups first, it needs root
+        unistd::setgroups(&[gid])
+            .and_then(|_| unistd::setgid(gid))
+            .map_err(|e| format!("Unable to switch to group {}: {}", gid, e))?;
+    }
+    if let Some(user) = &user {
+        unistd::setuid(user.uid)
+            .map_err(|e| format!("Unable to switch to user {}: {}", user.name, e))?;
+        if unistd::setuid(Uid::from_raw(0)).is_ok() {
+            return Err(format!("Still able to regain root after switching to user {}", user.name).into());
+        }
+    }
+    clear_capabilities()?;
+
+    if unistd::geteuid().is_root() {
+        info!("Running as root, use --user to run as someone else once the sockets are open");
+    } else {
+        info!("Running as uid {} gid {}", unistd::geteuid(), unistd::getegid());
+    }
+    Ok(())
+}
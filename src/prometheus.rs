@@ -0,0 +1,384 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write as FmtWrite;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use log::*;
+
+use crate::config::{family, Target};
+use crate::dns::{Lookup, Outcome, RecordType};
+use crate::icmp::Rejection;
+use crate::pinger::Event;
+use crate::resolver::DnsEvent;
+use crate::stats::Sink;
+
+// Upper bounds of the rtt histogram buckets, in seconds
+const BUCKETS: [f64; 12] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5];
+
+#[derive(Default)]
+struct Series {
+    sent: u64,
+    received: u64,
+    lost: u64,
+    buckets: [u64; 12],
+    sum: f64,
+    last_seen: Option<f64>,
+}
+
+#[derive(Default)]
+struct LookupSeries {
+    statuses: BTreeMap<&'static str, u64>,
+    buckets: [u64; 12],
+    sum: f64,
+    count: u64,
+    changes: u64,
+}
+
+/// Per-target counters and rtt histograms, rendered in the Prometheus text format
+#[derive(Default)]
+pub struct Registry {
+    series: BTreeMap<(String, IpAddr), Series>,
+    // extra labels from the config, by host
+    labels: HashMap<String, String>,
+    // address changes and lookup failures, by host
+    dns: BTreeMap<String, (u64, u64)>,
+    lookups: BTreeMap<(String, RecordType), LookupSeries>,
+    rejected: BTreeMap<Rejection, u64>,
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// label names are restricted to [a-zA-Z_][a-zA-Z0-9_]*
+fn label_name(name: &str) -> String {
+    let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{}", name)
+    } else {
+        name
+    }
+}
+
+impl Registry {
+    fn series(&mut self, host: &str, addr: IpAddr) -> &mut Series {
+        self.series.entry((host.to_string(), addr)).or_default()
+    }
+
+    pub fn targets(&mut self, targets: &[Target]) {
+        self.labels = targets.iter().map(|t| {
+            let labels = t.labels.iter()
+                .map(|(k, v)| format!(",{}=\"{}\"", label_name(k), escape(v)))
+                .collect::<String>();
+            (t.host.clone(), labels)
+        }).collect();
+    }
+
+    pub fn sent(&mut self, host: &str, addr: IpAddr) {
+        self.series(host, addr).sent += 1;
+    }
+
+    pub fn record(&mut self, event: &Event) {
+        // sites retired since the request was sent stay forgotten
+        let series = match event {
+            Event::Reply { host, addr, .. } | Event::Timeout { host, addr, .. } => self.series.get_mut(&(host.clone(), *addr)),
+            _ => None
+        };
+        let series = match series {
+            Some(series) => series,
+            None => return
+        };
+        match event {
+            Event::Reply { rtt, .. } => {
+                let seconds = rtt.as_secs_f64();
+                series.received += 1;
+                series.sum += seconds;
+                for (i, le) in BUCKETS.iter().enumerate() {
+                    if seconds <= *le {
+                        series.buckets[i] += 1;
+                    }
+                }
+                let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+                series.last_seen = Some(now.as_secs_f64());
+            }
+            Event::Timeout { .. } => {
+                series.lost += 1;
+            }
+            _ => {}
+        }
+    }
+
+    /// Drop the series of sites that are no longer pinged
+    pub fn retain(&mut self, sites: &HashSet<(String, IpAddr)>) {
+        let retired: Vec<_> = self.series.keys().filter(|key| !sites.contains(*key)).cloned().collect();
+        for key in retired {
+            self.series.remove(&key);
+        }
+    }
+
+    pub fn dns(&mut self, event: &DnsEvent) {
+        let counts = self.dns.entry(event.host().to_string()).or_default();
+        match event {
+            DnsEvent::Changed { .. } => counts.0 += 1,
+            DnsEvent::Failed { .. } => counts.1 += 1,
+        }
+    }
+
+    pub fn lookup(&mut self, lookup: &Lookup) {
+        let series = self.lookups.entry((lookup.host.clone(), lookup.record)).or_default();
+        *series.statuses.entry(lookup.outcome.status()).or_default() += 1;
+        if lookup.outcome != Outcome::Timeout {
+            let seconds = lookup.latency.as_secs_f64();
+            series.count += 1;
+            series.sum += seconds;
+            for (i, le) in BUCKETS.iter().enumerate() {
+                if seconds <= *le {
+                    series.buckets[i] += 1;
+                }
+            }
+        }
+        if lookup.changed {
+            series.changes += 1;
+        }
+    }
+
+    pub fn rejected(&mut self, reason: Rejection) {
+        *self.rejected.entry(reason).or_default() += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let labels: Vec<_> = self.series.iter().map(|((host, addr), series)| {
+            let extra = self.labels.get(host).map(|l| &**l).unwrap_or("");
+            (format!("host=\"{}\",address=\"{}\",family=\"{}\"{}", escape(host), addr, family(addr), extra), series)
+        }).collect();
+
+        writeln!(out, "# HELP pinglogger_sent_total Echo requests sent").unwrap();
+        writeln!(out, "# TYPE pinglogger_sent_total counter").unwrap();
+        for (l, s) in labels.iter() {
+            writeln!(out, "pinglogger_sent_total{{{}}} {}", l, s.sent).unwrap();
+        }
+
+        writeln!(out, "# HELP pinglogger_received_total Echo replies received").unwrap();
+        writeln!(out, "# TYPE pinglogger_received_total counter").unwrap();
+        for (l, s) in labels.iter() {
+            writeln!(out, "pinglogger_received_total{{{}}} {}", l, s.received).unwrap();
+        }
+
+        writeln!(out, "# HELP pinglogger_lost_total Echo requests that timed out").unwrap();
+        writeln!(out, "# TYPE pinglogger_lost_total counter").unwrap();
+        for (l, s) in labels.iter() {
+            writeln!(out, "pinglogger_lost_total{{{}}} {}", l, s.lost).unwrap();
+        }
+
+        writeln!(out, "# HELP pinglogger_rtt_seconds Round trip time of echo replies").unwrap();
+        writeln!(out, "# TYPE pinglogger_rtt_seconds histogram").unwrap();
+        for (l, s) in labels.iter() {
+            for (le, count) in BUCKETS.iter().zip(s.buckets.iter()) {
+                writeln!(out, "pinglogger_rtt_seconds_bucket{{{},le=\"{}\"}} {}", l, le, count).unwrap();
+            }
+            writeln!(out, "pinglogger_rtt_seconds_bucket{{{},le=\"+Inf\"}} {}", l, s.received).unwrap();
+            writeln!(out, "pinglogger_rtt_seconds_sum{{{}}} {}", l, s.sum).unwrap();
+            writeln!(out, "pinglogger_rtt_seconds_count{{{}}} {}", l, s.received).unwrap();
+        }
+
+        writeln!(out, "# HELP pinglogger_last_seen_timestamp_seconds Time of the last echo reply").unwrap();
+        writeln!(out, "# TYPE pinglogger_last_seen_timestamp_seconds gauge").unwrap();
+        for (l, s) in labels.iter() {
+            if let Some(t) = s.last_seen {
+                writeln!(out, "pinglogger_last_seen_timestamp_seconds{{{}}} {:.3}", l, t).unwrap();
+            }
+        }
+
+        let dns: Vec<_> = self.dns.iter().map(|(host, counts)| {
+            let extra = self.labels.get(host).map(|l| &**l).unwrap_or("");
+            (format!("host=\"{}\"{}", escape(host), extra), counts)
+        }).collect();
+
+        writeln!(out, "# HELP pinglogger_dns_changes_total Times a host resolved to a different set of addresses").unwrap();
+        writeln!(out, "# TYPE pinglogger_dns_changes_total counter").unwrap();
+        for (l, (changes, _)) in dns.iter() {
+            writeln!(out, "pinglogger_dns_changes_total{{{}}} {}", l, changes).unwrap();
+        }
+
+        writeln!(out, "# HELP pinglogger_dns_failures_total Failed lookups of a host").unwrap();
+        writeln!(out, "# TYPE pinglogger_dns_failures_total counter").unwrap();
+        for (l, (_, failures)) in dns.iter() {
+            writeln!(out, "pinglogger_dns_failures_total{{{}}} {}", l, failures).unwrap();
+        }
+
+        let lookups: Vec<_> = self.lookups.iter().map(|((host, record), series)| {
+            let extra = self.labels.get(host).map(|l| &**l).unwrap_or("");
+            (format!("host=\"{}\",type=\"{}\"{}", escape(host), record.name(), extra), series)
+        }).collect();
+
+        writeln!(out, "# HELP pinglogger_dns_lookups_total DNS probes by outcome").unwrap();
+        writeln!(out, "# TYPE pinglogger_dns_lookups_total counter").unwrap();
+        for (l, s) in lookups.iter() {
+            for (status, count) in s.statuses.iter() {
+                writeln!(out, "pinglogger_dns_lookups_total{{{},status=\"{}\"}} {}", l, status, count).unwrap();
+            }
+        }
+
+        writeln!(out, "# HELP pinglogger_dns_lookup_seconds Time for the nameserver to answer a DNS probe").unwrap();
+        writeln!(out, "# TYPE pinglogger_dns_lookup_seconds histogram").unwrap();
+        for (l, s) in lookups.iter() {
+            for (le, count) in BUCKETS.iter().zip(s.buckets.iter()) {
+                writeln!(out, "pinglogger_dns_lookup_seconds_bucket{{{},le=\"{}\"}} {}", l, le, count).unwrap();
+            }
+            writeln!(out, "pinglogger_dns_lookup_seconds_bucket{{{},le=\"+Inf\"}} {}", l, s.count).unwrap();
+            writeln!(out, "pinglogger_dns_lookup_seconds_sum{{{}}} {}", l, s.sum).unwrap();
+            writeln!(out, "pinglogger_dns_lookup_seconds_count{{{}}} {}", l, s.count).unwrap();
+        }
+
+        writeln!(out, "# HELP pinglogger_dns_answer_changes_total DNS probes answered with different addresses than the last").unwrap();
+        writeln!(out, "# TYPE pinglogger_dns_answer_changes_total counter").unwrap();
+        for (l, s) in lookups.iter() {
+            writeln!(out, "pinglogger_dns_answer_changes_total{{{}}} {}", l, s.changes).unwrap();
+        }
+
+        writeln!(out, "# HELP pinglogger_rejected_replies_total Replies to our requests that failed the payload checks").unwrap();
+        writeln!(out, "# TYPE pinglogger_rejected_replies_total counter").unwrap();
+        for (reason, count) in self.rejected.iter() {
+            writeln!(out, "pinglogger_rejected_replies_total{{reason=\"{}\"}} {}", reason.name(), count).unwrap();
+        }
+        out
+    }
+}
+
+/// Sink that updates a registry shared with the HTTP endpoint
+pub struct PrometheusSink {
+    pub registry: Arc<Mutex<Registry>>,
+}
+
+impl Sink for PrometheusSink {
+    fn targets(&mut self, targets: &[Target]) {
+        self.registry.lock().unwrap().targets(targets);
+    }
+
+    fn sent(&mut self, host: &str, addr: IpAddr) {
+        self.registry.lock().unwrap().sent(host, addr);
+    }
+
+    fn record(&mut self, event: &Event) {
+        self.registry.lock().unwrap().record(event);
+    }
+
+    fn dns(&mut self, event: &DnsEvent) {
+        self.registry.lock().unwrap().dns(event);
+    }
+
+    fn lookup(&mut self, lookup: &Lookup) {
+        self.registry.lock().unwrap().lookup(lookup);
+    }
+
+    fn rejected(&mut self, _addr: IpAddr, reason: Rejection) {
+        self.registry.lock().unwrap().rejected(reason);
+    }
+
+    fn retain(&mut self, sites: &HashSet<(String, IpAddr)>) {
+        self.registry.lock().unwrap().retain(sites);
+    }
+}
+
+fn handle(mut stream: TcpStream, registry: &Mutex<Registry>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    // read until the end of the request headers, we don't care about a body
+    let mut request = vec![];
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", registry.lock().unwrap().render()),
+        _ => ("404 Not Found", "Not Found\n".to_string()),
+    };
+
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body)?;
+    stream.flush()
+}
+
+/// Serve `/metrics` from the registry on a background thread
+pub fn serve(listener: TcpListener, registry: Arc<Mutex<Registry>>) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle(stream, &registry) {
+                        debug!("Prometheus request failed: {}", e);
+                    }
+                }
+                Err(e) => error!("Prometheus accept failed: {}", e)
+            }
+        }
+    });
+}
+
+/// Listen on addr and return a sink that feeds the endpoint
+pub fn prometheus(addr: &str) -> io::Result<PrometheusSink> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Serving Prometheus metrics on http://{}/metrics", listener.local_addr()?);
+    let registry = Arc::new(Mutex::new(Registry::default()));
+    serve(listener, registry.clone());
+    Ok(PrometheusSink { registry })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrape() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let registry = Arc::new(Mutex::new(Registry::default()));
+        serve(listener, registry.clone());
+
+        let mut sink = PrometheusSink { registry };
+        let target: IpAddr = "::1".parse().unwrap();
+        sink.sent("localhost", target);
+        sink.sent("localhost", target);
+        sink.record(&Event::Reply { host: "localhost".to_string(), addr: target, seq: 0, ident: 1, ttl: 64, size: 64, rtt: Duration::from_millis(3) });
+        sink.record(&Event::Timeout { host: "localhost".to_string(), addr: target, seq: 1, ident: 1 });
+        sink.dns(&DnsEvent::Failed { host: "localhost".to_string(), error: "timed out".to_string(), retry: Duration::from_secs(1) });
+        sink.lookup(&Lookup { host: "localhost".to_string(), record: RecordType::Aaaa, server: "[::1]:53".parse().unwrap(), latency: Duration::from_millis(20), outcome: Outcome::NxDomain, changed: false });
+        sink.rejected(target, Rejection::BadMac);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let labels = "host=\"localhost\",address=\"::1\",family=\"ipv6\"";
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains(&format!("pinglogger_sent_total{{{}}} 2", labels)));
+        assert!(response.contains(&format!("pinglogger_lost_total{{{}}} 1", labels)));
+        assert!(response.contains(&format!("pinglogger_rtt_seconds_bucket{{{},le=\"0.0025\"}} 0", labels)));
+        assert!(response.contains(&format!("pinglogger_rtt_seconds_bucket{{{},le=\"0.005\"}} 1", labels)));
+        assert!(response.contains(&format!("pinglogger_rtt_seconds_count{{{}}} 1", labels)));
+        assert!(response.contains(&format!("pinglogger_last_seen_timestamp_seconds{{{}}}", labels)));
+        assert!(response.contains("pinglogger_dns_changes_total{host=\"localhost\"} 0"));
+        assert!(response.contains("pinglogger_dns_failures_total{host=\"localhost\"} 1"));
+        assert!(response.contains("pinglogger_dns_lookups_total{host=\"localhost\",type=\"aaaa\",status=\"nxdomain\"} 1"));
+        assert!(response.contains("pinglogger_dns_lookup_seconds_bucket{host=\"localhost\",type=\"aaaa\",le=\"0.025\"} 1"));
+        assert!(response.contains("pinglogger_rejected_replies_total{reason=\"bad_mac\"} 1"));
+
+        // retired sites are dropped, and their late results ignored
+        sink.retain(&HashSet::new());
+        sink.record(&Event::Timeout { host: "localhost".to_string(), addr: target, seq: 2, ident: 1 });
+        assert!(!sink.registry.lock().unwrap().render().contains(labels));
+    }
+}
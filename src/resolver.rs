@@ -0,0 +1,294 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{after, bounded, never, select, unbounded, Receiver, Sender};
+use log::*;
+
+use crate::config::{ResolverConfig, Target};
+use crate::hosts;
+use crate::pinger::{lookup, target_sites, PingTargets};
+
+/// Lookups at a time, so one slow host doesn't hold up the others
+const LOOKUP_THREADS: usize = 4;
+
+/// Something that happened while re-resolving a host
+#[derive(Clone, Debug, PartialEq)]
+pub enum DnsEvent {
+    /// The host's addresses changed, sites are started and retired to match
+    Changed { host: String, added: Vec<IpAddr>, removed: Vec<IpAddr> },
+    /// The lookup failed, the host keeps its last known addresses, if any,
+    /// and is looked up again after retry
+    Failed { host: String, error: String, retry: Duration },
+}
+
+impl DnsEvent {
+    pub fn host(&self) -> &str {
+        match self {
+            DnsEvent::Changed { host, .. } | DnsEvent::Failed { host, .. } => host
+        }
+    }
+}
+
+impl fmt::Display for DnsEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DnsEvent::Changed { host, added, removed } => {
+                write!(f, "{} changed addresses", host)?;
+                if !added.is_empty() {
+                    write!(f, ", added {}", join(added))?;
+                }
+                if !removed.is_empty() {
+                    write!(f, ", removed {}", join(removed))?;
+                }
+                Ok(())
+            }
+            DnsEvent::Failed { host, error, retry } => write!(f, "Unable to resolve {}, retrying in {:?}: {}", host, retry, error)
+        }
+    }
+}
+
+fn join(addrs: &[IpAddr]) -> String {
+    addrs.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(" ")
+}
+
+// Compare a fresh lookup with the last one, None if nothing changed
+fn diff(host: &str, old: &[IpAddr], new: &[IpAddr]) -> Option<DnsEvent> {
+    let old_set: HashSet<_> = old.iter().collect();
+    let new_set: HashSet<_> = new.iter().collect();
+    let added: Vec<IpAddr> = new.iter().filter(|a| !old_set.contains(a)).cloned().collect();
+    let removed: Vec<IpAddr> = old.iter().filter(|a| !new_set.contains(a)).cloned().collect();
+    if added.is_empty() && removed.is_empty() {
+        None
+    } else {
+        Some(DnsEvent::Changed { host: host.to_string(), added, removed })
+    }
+}
+
+// Wait before retrying a host that has failed this many times in a row
+fn backoff(config: &ResolverConfig, failures: u32) -> Duration {
+    (config.retry * 2u32.pow(failures.saturating_sub(1).min(16))).min(config.max_retry)
+}
+
+// A host being looked up, pending until its first successful lookup
+struct Host {
+    addrs: Option<Vec<IpAddr>>,
+    failures: u32,
+    /// When to look it up next, None if only on reload
+    due: Option<Instant>,
+}
+
+struct State {
+    targets: Vec<Target>,
+    hosts: HashMap<String, Host>,
+}
+
+/// Keeps the sites of `PingTargets` in step with DNS. Hosts are looked up
+/// a few at a time and pinged as soon as they resolve. Failed lookups are
+/// retried with backoff, and hosts that fail keep their last addresses.
+pub struct Resolver {
+    pinger: Arc<PingTargets>,
+    state: Mutex<State>,
+    config: ResolverConfig,
+    events: Sender<DnsEvent>,
+    wake: (Sender<()>, Receiver<()>),
+}
+
+impl Resolver {
+    /// Start with every host pending, they're looked up by `run`. Changes
+    /// and failures are reported on events.
+    pub fn new(pinger: Arc<PingTargets>, targets: Vec<Target>, config: &ResolverConfig, events: Sender<DnsEvent>) -> Self {
+        let resolver = Resolver {
+            pinger,
+            state: Mutex::new(State { targets: vec![], hosts: HashMap::new() }),
+            config: config.clone(),
+            events,
+            wake: bounded(1),
+        };
+        resolver.set_targets(targets);
+        resolver
+    }
+
+    /// Replace the targets. Sites of known hosts are updated straight away,
+    /// and every host is looked up again by `run`.
+    pub fn set_targets(&self, targets: Vec<Target>) {
+        {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let mut hosts = HashMap::new();
+            for target in targets.iter() {
+                // addresses, including those from ranges, need no lookup
+                if let Some(addr) = hosts::literal(&target.host) {
+                    hosts.insert(target.host.clone(), Host { addrs: Some(vec![addr.ip()]), failures: 0, due: None });
+                    continue;
+                }
+                let mut host = state.hosts.remove(&target.host)
+                    .unwrap_or(Host { addrs: None, failures: 0, due: None });
+                host.due = Some(now);
+                hosts.insert(target.host.clone(), host);
+            }
+            state.hosts = hosts;
+            state.targets = targets;
+        }
+        self.update_sites();
+        let _ = self.wake.0.try_send(());
+    }
+
+    /// Whether every host has resolved or failed at least once
+    pub fn settled(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.hosts.values().all(|host| host.addrs.is_some() || host.failures > 0)
+    }
+
+    /// Hosts that haven't resolved yet
+    pub fn pending(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut pending: Vec<_> = state.hosts.iter()
+            .filter(|(_, host)| host.addrs.is_none())
+            .map(|(name, _)| name.clone())
+            .collect();
+        pending.sort();
+        pending
+    }
+
+    fn update_sites(&self) {
+        let state = self.state.lock().unwrap();
+        let sites = state.targets.iter()
+            .flat_map(|t| {
+                state.hosts.get(&t.host)
+                    .and_then(|host| host.addrs.as_ref())
+                    .map(|addrs| target_sites(t, addrs))
+                    .unwrap_or_default()
+            })
+            .collect();
+        match self.pinger.update(sites) {
+            Ok((added, removed)) if added > 0 || removed > 0 => {
+                info!("Targets updated, {} sites added and {} removed", added, removed);
+            }
+            Ok(_) => {}
+            Err(e) => error!("Unable to update targets, keeping the current ones: {}", e)
+        }
+    }
+
+    // Record the result of looking up name
+    fn apply(&self, name: &str, result: io::Result<Vec<IpAddr>>) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let interval = self.config.interval;
+        // the host may have been dropped by a reload while it was looked up
+        let host = match state.hosts.get_mut(name) {
+            Some(host) => host,
+            None => return
+        };
+
+        match result {
+            Ok(new) => {
+                match &host.addrs {
+                    Some(old) => {
+                        if let Some(event) = diff(name, old, &new) {
+                            let _ = self.events.send(event);
+                        }
+                    }
+                    None => info!("Resolved {} to {}", name, join(&new))
+                }
+                host.addrs = Some(new);
+                host.failures = 0;
+                host.due = if interval == Duration::from_secs(0) { None } else { Some(now + interval) };
+            }
+            Err(e) => {
+                host.failures += 1;
+                let retry = backoff(&self.config, host.failures);
+                host.due = Some(now + retry);
+                let _ = self.events.send(DnsEvent::Failed { host: name.to_string(), error: e.to_string(), retry });
+            }
+        }
+    }
+
+    // Hosts due to be looked up that aren't already, and how long until the
+    // next one is
+    fn due(&self, looking_up: &HashSet<String>) -> (Vec<String>, Option<Duration>) {
+        let now = Instant::now();
+        let state = self.state.lock().unwrap();
+        let waiting = || state.hosts.iter().filter(|(name, _)| !looking_up.contains(*name));
+        let due = waiting()
+            .filter(|(_, host)| host.due.map(|t| t <= now).unwrap_or(false))
+            .map(|(name, _)| name.clone())
+            .collect();
+        let next = waiting()
+            .filter_map(|(_, host)| host.due)
+            .min()
+            .map(|t| t.saturating_duration_since(now));
+        (due, next)
+    }
+
+    /// Look hosts up as they fall due, or as soon as the targets change,
+    /// forever. Lookups are shared by a few threads, and the sites are
+    /// updated as each one finishes.
+    pub fn run(&self) {
+        let (jobs, queue) = unbounded::<String>();
+        let (done, results) = unbounded();
+        for _ in 0..LOOKUP_THREADS {
+            let (queue, done) = (queue.clone(), done.clone());
+            thread::spawn(move || {
+                for name in queue.iter() {
+                    let result = lookup(&name);
+                    if done.send((name, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        let mut looking_up = HashSet::new();
+        loop {
+            let (due, next) = self.due(&looking_up);
+            let free = LOOKUP_THREADS - looking_up.len();
+            // with every thread busy, wait for one to finish
+            let timeout = if due.len() > free { None } else { next };
+            for name in due.into_iter().take(free) {
+                looking_up.insert(name.clone());
+                let _ = jobs.send(name);
+            }
+
+            let timeout = timeout.map(after).unwrap_or_else(never);
+            select! {
+                recv(results) -> result => {
+                    if let Ok((name, result)) = result {
+                        looking_up.remove(&name);
+                        self.apply(&name, result);
+                        self.update_sites();
+                    }
+                }
+                recv(self.wake.1) -> _ => (),
+                recv(timeout) -> _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes() {
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        let c: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(diff("x", &[a, b], &[a, b]), None);
+        assert_eq!(diff("x", &[a, b], &[b, c]), Some(DnsEvent::Changed { host: "x".to_string(), added: vec![c], removed: vec![a] }));
+        assert_eq!(diff("x", &[a, b], &[b, c]).unwrap().to_string(), "x changed addresses, added 2001:db8::1, removed 192.0.2.1");
+    }
+
+    #[test]
+    fn retries() {
+        let config = ResolverConfig::default();
+        let waits: Vec<_> = (1..=8).map(|n| backoff(&config, n).as_secs()).collect();
+        assert_eq!(waits, [1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(backoff(&config, u32::MAX), Duration::from_secs(60));
+    }
+}
@@ -1,28 +1,870 @@
 use dipstick::*;
-use std::time::Duration;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use log::*;
 use slugify::slugify;
+use serde::Deserialize;
+use crate::config::{family, Target};
+use crate::dns::{Lookup, Outcome};
+use crate::icmp::Rejection;
+use crate::pinger::Event;
+use crate::resolver::DnsEvent;
+use crate::writer::{write_messages, Destination, Writer};
 
+/// A destination for probe results
+pub trait Sink {
+    /// The set of targets changed, for sinks that name or label series
+    fn targets(&mut self, _targets: &[Target]) {}
+    /// An echo request was sent
+    fn sent(&mut self, _host: &str, _addr: IpAddr) {}
+    /// An echo request completed
+    fn record(&mut self, event: &Event);
+    /// A host changed addresses or failed to resolve
+    fn dns(&mut self, _event: &DnsEvent) {}
+    /// A DNS probe completed
+    fn lookup(&mut self, _lookup: &Lookup) {}
+    /// A reply to one of our requests failed the payload checks
+    fn rejected(&mut self, _addr: IpAddr, _reason: Rejection) {}
+    /// Called periodically so buffered results can be written out
+    fn flush(&mut self) {}
+    /// Only these sites, by host and address, are pinged now, for sinks that
+    /// keep series for each
+    fn retain(&mut self, _sites: &HashSet<(String, IpAddr)>) {}
+}
+
+/// Fans probe results out to every configured sink
+#[derive(Default)]
 pub struct Metrics {
-    pub statsd: Statsd
+    pub sinks: Vec<Box<dyn Sink>>
 }
 
 impl Metrics {
-    pub fn update(&mut self, d: &Duration, host: &str) {
-        let slug = slugify!(host);
-        println!("{} {:?} {}", slug, d, d.as_micros() as u64);
-        self.statsd.metrics().counter(&*slug).count(1);
-        self.statsd.metrics().timer(&*slug).interval_us(d.as_micros() as u64); 
+    pub fn add<S: Sink + 'static>(&mut self, sink: S) {
+        self.sinks.push(Box::new(sink));
+    }
+
+    pub fn targets(&mut self, targets: &[Target]) {
+        self.sinks.iter_mut().for_each(|sink| sink.targets(targets));
+    }
+
+    pub fn sent(&mut self, host: &str, addr: IpAddr) {
+        self.sinks.iter_mut().for_each(|sink| sink.sent(host, addr));
+    }
+
+    pub fn update(&mut self, event: &Event) {
+        self.sinks.iter_mut().for_each(|sink| sink.record(event));
+    }
+
+    pub fn dns(&mut self, event: &DnsEvent) {
+        self.sinks.iter_mut().for_each(|sink| sink.dns(event));
+    }
+
+    pub fn lookup(&mut self, lookup: &Lookup) {
+        self.sinks.iter_mut().for_each(|sink| sink.lookup(lookup));
+    }
+
+    pub fn rejected(&mut self, addr: IpAddr, reason: Rejection) {
+        self.sinks.iter_mut().for_each(|sink| sink.rejected(addr, reason));
+    }
+
+    pub fn flush(&mut self) {
+        self.sinks.iter_mut().for_each(|sink| sink.flush());
+    }
+
+    pub fn retain(&mut self, sites: &HashSet<(String, IpAddr)>) {
+        self.sinks.iter_mut().for_each(|sink| sink.retain(sites));
+    }
+}
+
+/// Which metrics server to send results to
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Statsd,
+    Graphite,
+    None,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "statsd" => Ok(Backend::Statsd),
+            "graphite" => Ok(Backend::Graphite),
+            "none" => Ok(Backend::None),
+            _ => Err(format!("unknown metrics backend: {}", s))
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub backend: Backend,
+    pub host: String,
+    /// Defaults to the standard port for the backend
+    pub port: Option<u16>,
+    pub prefix: String,
+    /// Send batches to graphite using the pickle protocol
+    pub pickle: bool,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            backend: Backend::Statsd,
+            host: "localhost".to_string(),
+            port: None,
+            prefix: "app".to_string(),
+            pickle: false,
+        }
+    }
+}
+
+/// Metric names of targets, the host name unless the config gives another
+#[derive(Default)]
+pub struct Names {
+    names: HashMap<String, String>,
+}
+
+impl Names {
+    pub fn update(&mut self, targets: &[Target]) {
+        self.names = targets.iter().map(|t| (t.host.clone(), t.name.clone())).collect();
+    }
+
+    pub fn slug(&self, host: &str) -> String {
+        let name = self.names.get(host).map(|name| &**name).unwrap_or(host);
+        slugify!(name)
+    }
+}
+
+/// The part of a metric name that identifies one of a host's addresses,
+/// like `ipv4.192_0_2_1`
+pub fn address_key(addr: &IpAddr) -> String {
+    let name: String = addr.to_string().chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    format!("{}.{}", family(addr), name)
+}
+
+/// Sends results through a dipstick output, using slugified host names as metric names
+pub struct DipstickSink {
+    pub scope: Arc<dyn InputScope + Send + Sync>,
+    pub names: Names,
+}
+
+impl DipstickSink {
+    pub fn update(&mut self, d: &Duration, host: &str, addr: &IpAddr) {
+        let slug = format!("{}.{}", self.names.slug(host), address_key(addr));
+        self.scope.counter(&slug).count(1);
+        self.scope.timer(&slug).interval_us(d.as_micros() as u64);
+    }
+
+    /// Count an echo request to host that timed out without a reply
+    pub fn lost(&mut self, host: &str, addr: &IpAddr) {
+        let slug = self.names.slug(host);
+        self.scope.counter(&format!("{}.{}.lost", slug, address_key(addr))).count(1);
+    }
+}
+
+impl Sink for DipstickSink {
+    fn targets(&mut self, targets: &[Target]) {
+        self.names.update(targets);
+    }
+
+    fn sent(&mut self, host: &str, addr: IpAddr) {
+        let slug = self.names.slug(host);
+        self.scope.counter(&format!("{}.{}.sent", slug, address_key(&addr))).count(1);
+    }
+
+    fn record(&mut self, event: &Event) {
+        match event {
+            Event::Reply { host, addr, rtt, .. } => self.update(rtt, host, addr),
+            Event::Timeout { host, addr, .. } => self.lost(host, addr),
+            _ => {}
+        }
+    }
+
+    fn dns(&mut self, event: &DnsEvent) {
+        let slug = self.names.slug(event.host());
+        let name = match event {
+            DnsEvent::Changed { .. } => "dns.changes",
+            DnsEvent::Failed { .. } => "dns.failures",
+        };
+        self.scope.counter(&format!("{}.{}", slug, name)).count(1);
+    }
+
+    fn lookup(&mut self, lookup: &Lookup) {
+        let name = format!("{}.dns.{}", self.names.slug(&lookup.host), lookup.record.name());
+        if lookup.outcome != Outcome::Timeout {
+            self.scope.timer(&name).interval_us(lookup.latency.as_micros() as u64);
+        }
+        self.scope.counter(&format!("{}.{}", name, lookup.outcome.status())).count(1);
+        if lookup.changed {
+            self.scope.counter(&format!("{}.changes", name)).count(1);
+        }
+    }
+
+    fn rejected(&mut self, _addr: IpAddr, reason: Rejection) {
+        self.scope.counter(&format!("rejected.{}", reason.name())).count(1);
+    }
+}
+
+const CARBON_TIMEOUT: Duration = Duration::from_secs(1);
+// Points in each pickled message
+const PICKLE_BATCH: usize = 500;
+
+/// The connection to carbon, used from the sink's writer thread
+struct Carbon {
+    addr: (String, u16),
+    pickle: bool,
+    socket: Option<TcpStream>,
+}
+
+impl Carbon {
+    fn connect(&mut self) -> io::Result<&mut TcpStream> {
+        if self.socket.is_none() {
+            let addrs: Vec<_> = (&*self.addr.0, self.addr.1).to_socket_addrs()?.collect();
+            let mut result = Err(io::Error::new(io::ErrorKind::NotFound, "no addresses"));
+            for addr in addrs {
+                result = TcpStream::connect_timeout(&addr, CARBON_TIMEOUT);
+                if result.is_ok() {
+                    break;
+                }
+            }
+            let socket = result?;
+            socket.set_write_timeout(Some(CARBON_TIMEOUT))?;
+            info!("Connected to carbon at {}:{}", self.addr.0, self.addr.1);
+            self.socket = Some(socket);
+        }
+        Ok(self.socket.as_mut().unwrap())
     }
 }
 
-pub fn metrics(name: &str) -> Metrics {
-    let statsd = dipstick::Statsd::send_to("localhost:8125")
-        .expect("Connected")
-        .named(name);
+impl Destination<(String, u64, f64)> for Carbon {
+    fn name(&self) -> String {
+        format!("carbon at {}:{}", self.addr.0, self.addr.1)
+    }
+
+    fn write(&mut self, points: &[(String, u64, f64)]) -> std::result::Result<(), (usize, io::Error)> {
+        // a line each, or batches of points, so only whole ones count as written
+        let (messages, size): (Vec<_>, _) = if self.pickle {
+            (points.chunks(PICKLE_BATCH).map(|batch| pickle(batch.iter())).collect(), PICKLE_BATCH)
+        } else {
+            (points.iter().map(|(path, t, value)| format!("{} {} {}\n", path, value, t).into_bytes()).collect(), 1)
+        };
+        let socket = self.connect().map_err(|e| (0, e))?;
+        let result = write_messages(socket, &messages).map_err(|(n, e)| ((n * size).min(points.len()), e));
+        if result.is_err() {
+            // reconnect, rather than finishing a message that was cut short
+            self.socket = None;
+        }
+        result
+    }
+}
 
-    let metrics = Metrics {
-        statsd
-    };
-    metrics
+/// Writes rtt and loss series for each host straight to carbon, using the
+/// plaintext protocol or batches in the pickle protocol.
+pub struct CarbonSink {
+    prefix: String,
+    names: Names,
+    writer: Writer<(String, u64, f64)>,
 }
 
+impl CarbonSink {
+    pub fn new(host: &str, port: u16, prefix: &str, pickle: bool) -> Self {
+        let carbon = Carbon { addr: (host.to_string(), port), pickle, socket: None };
+        CarbonSink {
+            prefix: prefix.to_string(),
+            names: Names::default(),
+            writer: Writer::new(carbon),
+        }
+    }
+
+    fn push(&mut self, host: &str, name: &str, value: f64) {
+        let path = format!("{}.{}.{}", self.prefix, self.names.slug(host), name);
+        self.push_path(path, value);
+    }
+
+    fn push_path(&mut self, path: String, value: f64) {
+        let t = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        self.writer.push((path, t, value));
+    }
+}
+
+impl Sink for CarbonSink {
+    fn targets(&mut self, targets: &[Target]) {
+        self.names.update(targets);
+    }
+
+    fn record(&mut self, event: &Event) {
+        match event {
+            Event::Reply { host, addr, rtt, .. } => {
+                let key = address_key(addr);
+                self.push(host, &format!("{}.rtt", key), rtt.as_secs_f64() * 1000.);
+                self.push(host, &format!("{}.loss", key), 0.);
+            }
+            Event::Timeout { host, addr, .. } => self.push(host, &format!("{}.loss", address_key(addr)), 100.),
+            _ => {}
+        }
+    }
+
+    fn dns(&mut self, event: &DnsEvent) {
+        match event {
+            DnsEvent::Changed { host, .. } => self.push(host, "dns.changes", 1.),
+            DnsEvent::Failed { host, .. } => self.push(host, "dns.failures", 1.),
+        }
+    }
+
+    fn lookup(&mut self, lookup: &Lookup) {
+        let name = format!("dns.{}", lookup.record.name());
+        if lookup.outcome != Outcome::Timeout {
+            self.push(&lookup.host, &format!("{}.latency", name), lookup.latency.as_secs_f64() * 1000.);
+        }
+        self.push(&lookup.host, &format!("{}.{}", name, lookup.outcome.status()), 1.);
+        if lookup.changed {
+            self.push(&lookup.host, &format!("{}.changes", name), 1.);
+        }
+    }
+
+    fn rejected(&mut self, _addr: IpAddr, reason: Rejection) {
+        let path = format!("{}.rejected.{}", self.prefix, reason.name());
+        self.push_path(path, 1.);
+    }
+
+    fn flush(&mut self) {
+        self.writer.report();
+    }
+}
+
+/// Encode points as a pickled list of (path, (timestamp, value)) tuples,
+/// prefixed with its length as carbon's pickle receiver expects
+fn pickle<'a, I: Iterator<Item=&'a (String, u64, f64)>>(points: I) -> Vec<u8> {
+    // protocol 2, empty list, mark
+    let mut body = vec![0x80, 2, b']', b'('];
+    for (path, t, value) in points {
+        body.push(b'X');
+        body.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        body.extend_from_slice(path.as_bytes());
+        body.push(b'J');
+        body.extend_from_slice(&(*t as i32).to_le_bytes());
+        body.push(b'G');
+        body.extend_from_slice(&value.to_be_bytes());
+        // (t, value), then (path, (t, value))
+        body.push(0x86);
+        body.push(0x86);
+    }
+    // appends, stop
+    body.push(b'e');
+    body.push(b'.');
+
+    let mut data = (body.len() as u32).to_be_bytes().to_vec();
+    data.extend(body);
+    data
+}
+
+/// Connect to the configured metrics server, or None if there isn't one.
+/// Failing to set up the sink is logged rather than fatal, so pinging continues.
+pub fn sink(config: &MetricsConfig) -> Option<Box<dyn Sink>> {
+    match config.backend {
+        Backend::Statsd => {
+            let addr = (&*config.host, config.port.unwrap_or(8125));
+            match dipstick::Statsd::send_to(addr) {
+                Ok(statsd) => Some(Box::new(DipstickSink { scope: statsd.named(&*config.prefix).input_dyn(), names: Names::default() })),
+                Err(e) => {
+                    error!("Unable to send metrics to statsd at {}: {}", config.host, e);
+                    None
+                }
+            }
+        }
+        Backend::Graphite => {
+            let port = config.port.unwrap_or(if config.pickle { 2004 } else { 2003 });
+            // connects lazily and keeps retrying, so this can't fail
+            Some(Box::new(CarbonSink::new(&config.host, port, &config.prefix, config.pickle)))
+        }
+        Backend::None => None
+    }
+}
+
+/// How many recent probes the rolling statistics cover
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Window {
+    /// Last N probes
+    pub count: usize,
+    /// Probes in the last T
+    #[serde(with = "crate::config::seconds")]
+    pub duration: Duration,
+}
+
+impl Default for Window {
+    fn default() -> Self {
+        Window {
+            count: 100,
+            duration: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Statistics over a set of probes, rtt values are None if nothing was received
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub transmitted: u64,
+    pub received: u64,
+    pub duplicates: u64,
+    pub loss: f64,
+    pub min: Option<Duration>,
+    pub avg: Option<Duration>,
+    pub max: Option<Duration>,
+    pub mdev: Option<Duration>,
+    pub jitter: Option<Duration>,
+    pub p50: Option<Duration>,
+    pub p90: Option<Duration>,
+    pub p99: Option<Duration>,
+}
+
+impl Summary {
+    fn from_samples<'a, I: Iterator<Item=&'a Option<Duration>>>(samples: I) -> Summary {
+        let mut summary = Summary::default();
+        let mut rtts = vec![];
+        // RFC 3550 interarrival jitter, using the difference between
+        // consecutive round trip times as the transit time difference
+        let mut jitter: Option<f64> = None;
+        let mut last: Option<f64> = None;
+
+        for sample in samples {
+            summary.transmitted += 1;
+            if let Some(rtt) = sample {
+                let rtt = rtt.as_nanos() as f64;
+                if let Some(last) = last {
+                    let j = jitter.unwrap_or(0.);
+                    jitter = Some(j + ((rtt - last).abs() - j) / 16.);
+                }
+                last = Some(rtt);
+                rtts.push(rtt);
+            }
+        }
+
+        summary.received = rtts.len() as u64;
+        if summary.transmitted > 0 {
+            summary.loss = 100. * (summary.transmitted - summary.received) as f64 / summary.transmitted as f64;
+        }
+        if !rtts.is_empty() {
+            let n = rtts.len() as f64;
+            let avg = rtts.iter().sum::<f64>() / n;
+            let avg2 = rtts.iter().map(|x| x * x).sum::<f64>() / n;
+            rtts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+            summary.min = Some(nanos(rtts[0]));
+            summary.max = Some(nanos(rtts[rtts.len() - 1]));
+            summary.avg = Some(nanos(avg));
+            summary.mdev = Some(nanos((avg2 - avg * avg).max(0.).sqrt()));
+            summary.jitter = jitter.map(nanos);
+            summary.p50 = Some(nanos(percentile(&rtts, 50.)));
+            summary.p90 = Some(nanos(percentile(&rtts, 90.)));
+            summary.p99 = Some(nanos(percentile(&rtts, 99.)));
+        }
+        summary
+    }
+}
+
+fn nanos(x: f64) -> Duration {
+    Duration::from_nanos(x as u64)
+}
+
+fn millis(d: Option<Duration>) -> String {
+    match d {
+        Some(d) => format!("{:.3}", d.as_secs_f64() * 1000.),
+        None => "-".to_string()
+    }
+}
+
+// nearest-rank percentile of sorted values
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p / 100. * sorted.len() as f64).ceil() as usize;
+    sorted[rank.max(1) - 1]
+}
+
+// Width of the histogram buckets for percentiles since startup, each is 1%
+// wider than the last
+const BUCKET_RATIO: f64 = 1.01;
+
+/// Running totals of every probe that completed, in constant space. Since
+/// startup percentiles come from a histogram, so they're within 1%.
+#[derive(Clone, Debug, Default)]
+struct Totals {
+    completed: u64,
+    received: u64,
+    sum: f64,
+    sum2: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+    jitter: Option<f64>,
+    last: Option<f64>,
+    buckets: BTreeMap<u32, u64>,
+}
+
+impl Totals {
+    /// Add a completed probe, rtt is None if it was lost
+    fn add(&mut self, rtt: Option<Duration>) {
+        self.completed += 1;
+        let rtt = match rtt {
+            Some(rtt) => rtt.as_nanos() as f64,
+            None => return
+        };
+        // as in Summary::from_samples
+        if let Some(last) = self.last {
+            let j = self.jitter.unwrap_or(0.);
+            self.jitter = Some(j + ((rtt - last).abs() - j) / 16.);
+        }
+        self.last = Some(rtt);
+        self.received += 1;
+        self.sum += rtt;
+        self.sum2 += rtt * rtt;
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        self.max = Some(self.max.map_or(rtt, |max| max.max(rtt)));
+        *self.buckets.entry(rtt.max(1.).log(BUCKET_RATIO) as u32).or_insert(0) += 1;
+    }
+
+    /// Combine with the totals of another target, jitter is only kept for a single target
+    fn merge(&mut self, other: &Totals) {
+        self.completed += other.completed;
+        self.received += other.received;
+        self.sum += other.sum;
+        self.sum2 += other.sum2;
+        self.min = self.min.into_iter().chain(other.min).fold(None, |m, x| Some(m.map_or(x, |m: f64| m.min(x))));
+        self.max = self.max.into_iter().chain(other.max).fold(None, |m, x| Some(m.map_or(x, |m: f64| m.max(x))));
+        self.jitter = None;
+        self.last = None;
+        for (bucket, count) in other.buckets.iter() {
+            *self.buckets.entry(*bucket).or_insert(0) += count;
+        }
+    }
+
+    // nearest-rank percentile, the middle of its bucket kept within min and max
+    fn percentile(&self, p: f64) -> Option<f64> {
+        let rank = ((p / 100. * self.received as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        let (bucket, _) = self.buckets.iter().find(|(_, count)| {
+            seen += *count;
+            seen >= rank
+        })?;
+        let middle = BUCKET_RATIO.powf(f64::from(*bucket) + 0.5);
+        Some(middle.max(self.min?).min(self.max?))
+    }
+
+    fn summary(&self) -> Summary {
+        let mut summary = Summary {
+            transmitted: self.completed,
+            received: self.received,
+            ..Summary::default()
+        };
+        if self.completed > 0 {
+            summary.loss = 100. * (self.completed - self.received) as f64 / self.completed as f64;
+        }
+        if self.received > 0 {
+            let n = self.received as f64;
+            let avg = self.sum / n;
+            summary.min = self.min.map(nanos);
+            summary.max = self.max.map(nanos);
+            summary.avg = Some(nanos(avg));
+            summary.mdev = Some(nanos((self.sum2 / n - avg * avg).max(0.).sqrt()));
+            summary.jitter = self.jitter.map(nanos);
+            summary.p50 = self.percentile(50.).map(nanos);
+            summary.p90 = self.percentile(90.).map(nanos);
+            summary.p99 = self.percentile(99.).map(nanos);
+        }
+        summary
+    }
+}
+
+/// Rolling statistics for a single target address
+pub struct TargetStats {
+    window: Window,
+    transmitted: u64,
+    duplicates: u64,
+    // every probe that completed, for the totals
+    totals: Totals,
+    // probes that completed recently, for the rolling windows
+    recent: VecDeque<(Instant, Option<Duration>)>,
+}
+
+impl TargetStats {
+    pub fn new(window: Window) -> Self {
+        TargetStats {
+            window,
+            transmitted: 0,
+            duplicates: 0,
+            totals: Totals::default(),
+            recent: VecDeque::new(),
+        }
+    }
+
+    pub fn sent(&mut self) {
+        self.transmitted += 1;
+    }
+
+    pub fn duplicate(&mut self) {
+        self.duplicates += 1;
+    }
+
+    /// Add a completed probe, rtt is None if it was lost
+    pub fn add(&mut self, rtt: Option<Duration>, at: Instant) {
+        self.totals.add(rtt);
+        self.recent.push_back((at, rtt));
+
+        // keep anything inside either window
+        while self.recent.len() > self.window.count {
+            match self.recent.front() {
+                Some((t, _)) if at.duration_since(*t) > self.window.duration => {
+                    self.recent.pop_front();
+                }
+                _ => break
+            }
+        }
+    }
+
+    pub fn transmitted(&self) -> u64 {
+        self.transmitted
+    }
+
+    /// Statistics since startup, in flight probes count as lost like ping does
+    pub fn total(&self) -> Summary {
+        let mut summary = self.totals.summary();
+        summary.transmitted = self.transmitted.max(summary.transmitted);
+        if summary.transmitted > 0 {
+            summary.loss = 100. * (summary.transmitted - summary.received) as f64 / summary.transmitted as f64;
+        }
+        summary.duplicates = self.duplicates;
+        summary
+    }
+
+    /// Statistics for the last `Window::count` completed probes
+    pub fn last(&self) -> Summary {
+        let skip = self.recent.len().saturating_sub(self.window.count);
+        Summary::from_samples(self.recent.iter().skip(skip).map(|(_, rtt)| rtt))
+    }
+
+    /// Statistics for the probes completed within `Window::duration` of now
+    pub fn recent(&self, now: Instant) -> Summary {
+        let duration = self.window.duration;
+        Summary::from_samples(self.recent.iter()
+            .filter(|(t, _)| now.saturating_duration_since(*t) <= duration)
+            .map(|(_, rtt)| rtt))
+    }
+}
+
+/// Rolling statistics for every target, keyed by host and address
+pub struct Statistics {
+    window: Window,
+    targets: BTreeMap<(String, IpAddr), TargetStats>,
+}
+
+impl Statistics {
+    pub fn new(window: Window) -> Self {
+        Statistics {
+            window,
+            targets: BTreeMap::new(),
+        }
+    }
+
+    fn target(&mut self, host: &str, addr: IpAddr) -> &mut TargetStats {
+        let window = self.window;
+        self.targets.entry((host.to_string(), addr))
+            .or_insert_with(|| TargetStats::new(window))
+    }
+
+    pub fn get(&self, host: &str, addr: IpAddr) -> Option<&TargetStats> {
+        self.targets.get(&(host.to_string(), addr))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&(String, IpAddr), &TargetStats)> {
+        self.targets.iter()
+    }
+
+    pub fn sent(&mut self, host: &str, addr: IpAddr) {
+        self.target(host, addr).sent();
+    }
+
+    pub fn record(&mut self, event: &Event) {
+        let now = Instant::now();
+        let (host, addr) = match event {
+            Event::Reply { host, addr, .. } | Event::Timeout { host, addr, .. } | Event::Duplicate { host, addr, .. } => (host, addr),
+            // already counted as lost when it timed out
+            Event::Late { .. } => return
+        };
+        // sites retired since the request was sent stay forgotten
+        let target = match self.targets.get_mut(&(host.clone(), *addr)) {
+            Some(target) => target,
+            None => return
+        };
+        match event {
+            Event::Reply { rtt, .. } => target.add(Some(*rtt), now),
+            Event::Timeout { .. } => target.add(None, now),
+            Event::Duplicate { .. } => target.duplicate(),
+            Event::Late { .. } => {}
+        }
+    }
+
+    /// Forget the targets that aren't among sites, which are no longer pinged
+    pub fn retain(&mut self, sites: &HashSet<(String, IpAddr)>) {
+        let retired: Vec<_> = self.targets.keys().filter(|key| !sites.contains(*key)).cloned().collect();
+        for key in retired {
+            self.targets.remove(&key);
+        }
+    }
+
+    /// Statistics over every target, since startup or within the window of now
+    pub fn overall(&self, now: Option<Instant>) -> Summary {
+        match now {
+            None => {
+                let mut totals = Totals::default();
+                for target in self.targets.values() {
+                    totals.merge(&target.totals);
+                }
+                totals.summary()
+            }
+            Some(now) => {
+                let duration = self.window.duration;
+                Summary::from_samples(self.targets.values()
+                    .flat_map(|t| t.recent.iter())
+                    .filter(|(t, _)| now.saturating_duration_since(*t) <= duration)
+                    .map(|(_, rtt)| rtt))
+            }
+        }
+    }
+
+    /// Print totals for every target, in the style of ping
+    pub fn print_summary(&self) {
+        for ((host, addr), stats) in self.iter() {
+            let s = stats.total();
+            println!("--- {} ({}) ping statistics ---", host, addr);
+            let dup = if s.duplicates > 0 { format!(", +{} duplicates", s.duplicates) } else { String::new() };
+            println!("{} packets transmitted, {} received{}, {:.1}% packet loss",
+                s.transmitted, s.received, dup, s.loss);
+            if s.received > 0 {
+                println!("rtt min/avg/max/mdev = {}/{}/{}/{} ms",
+                    millis(s.min), millis(s.avg), millis(s.max), millis(s.mdev));
+                println!("rtt p50/p90/p99 = {}/{}/{} ms, jitter = {} ms",
+                    millis(s.p50), millis(s.p90), millis(s.p99), millis(s.jitter));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn ms(x: u64) -> Option<Duration> {
+        Some(Duration::from_millis(x))
+    }
+
+    #[test]
+    fn summary() {
+        let samples = [ms(10), None, ms(20), ms(30), ms(40)];
+        let s = Summary::from_samples(samples.iter());
+        assert_eq!(s.transmitted, 5);
+        assert_eq!(s.received, 4);
+        assert_eq!(s.loss, 20.);
+        assert_eq!(s.min, ms(10));
+        assert_eq!(s.max, ms(40));
+        assert_eq!(s.avg, ms(25));
+        assert_eq!(s.p50, ms(20));
+        assert_eq!(s.p99, ms(40));
+        // sqrt(125) ms
+        assert_eq!(s.mdev.unwrap().as_micros(), 11180);
+        // three steps of 10ms: 0.625, 1.211, 1.760
+        assert_eq!(s.jitter.unwrap().as_micros(), 1760);
+    }
+
+    #[test]
+    fn carbon_plaintext() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut sink = CarbonSink::new("127.0.0.1", port, "app", false);
+        sink.record(&Event::Timeout { host: "cs.ubc.ca".to_string(), addr: "127.0.0.1".parse().unwrap(), seq: 0, ident: 1 });
+        // written and closed by the writer thread once the sink is gone
+        drop(sink);
+
+        let mut received = String::new();
+        listener.accept().unwrap().0.read_to_string(&mut received).unwrap();
+        let fields: Vec<_> = received.split_whitespace().collect();
+        assert_eq!(fields[..2], ["app.cs-ubc-ca.ipv4.127_0_0_1.loss", "100"]);
+    }
+
+    #[test]
+    fn carbon_pickle() {
+        let points = [("a.b".to_string(), 1, 0.5)];
+        let data = pickle(points.iter());
+        assert_eq!(data[..4], ((data.len() - 4) as u32).to_be_bytes());
+        assert_eq!(data[4..], [
+            0x80, 2, b']', b'(',
+            b'X', 3, 0, 0, 0, b'a', b'.', b'b',
+            b'J', 1, 0, 0, 0,
+            b'G', 0x3f, 0xe0, 0, 0, 0, 0, 0, 0,
+            0x86, 0x86, b'e', b'.'
+        ]);
+    }
+
+    #[test]
+    fn windows() {
+        let start = Instant::now();
+        let mut stats = TargetStats::new(Window { count: 2, duration: Duration::from_secs(10) });
+        stats.add(ms(10), start);
+        stats.add(None, start + Duration::from_secs(5));
+        stats.add(ms(30), start + Duration::from_secs(20));
+
+        assert_eq!(stats.total().transmitted, 3);
+        assert_eq!(stats.last().transmitted, 2);
+        assert_eq!(stats.last().loss, 50.);
+        let recent = stats.recent(start + Duration::from_secs(20));
+        assert_eq!(recent.transmitted, 1);
+        assert_eq!(recent.avg, ms(30));
+    }
+
+    #[test]
+    fn retire() {
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+        let timeout = |host: &str| Event::Timeout { host: host.to_string(), addr, seq: 0, ident: 1 };
+        let mut statistics = Statistics::new(Window::default());
+        statistics.sent("a", addr);
+        statistics.sent("b", addr);
+
+        let sites: HashSet<_> = vec![("a".to_string(), addr)].into_iter().collect();
+        statistics.retain(&sites);
+        // the request to b times out after it's retired
+        statistics.record(&timeout("a"));
+        statistics.record(&timeout("b"));
+        assert_eq!(statistics.get("a", addr).map(|s| s.total().loss), Some(100.));
+        assert!(statistics.get("b", addr).is_none());
+        assert_eq!(statistics.iter().count(), 1);
+    }
+
+    #[test]
+    fn totals() {
+        let samples = [ms(10), None, ms(20), ms(30), ms(40)];
+        let mut totals = Totals::default();
+        for rtt in samples.iter() {
+            totals.add(*rtt);
+        }
+        let s = totals.summary();
+        let exact = Summary::from_samples(samples.iter());
+        assert_eq!((s.transmitted, s.received, s.loss), (5, 4, 20.));
+        assert_eq!((s.min, s.max, s.avg, s.mdev, s.jitter), (exact.min, exact.max, exact.avg, exact.mdev, exact.jitter));
+        let close = |a: Option<Duration>, b: Option<Duration>| (a.unwrap().as_secs_f64() / b.unwrap().as_secs_f64() - 1.).abs() < 0.01;
+        assert!(close(s.p50, exact.p50));
+        assert_eq!(s.p99, ms(40));
+
+        let mut merged = Totals::default();
+        merged.merge(&totals);
+        merged.merge(&totals);
+        let m = merged.summary();
+        assert_eq!((m.transmitted, m.received, m.avg, m.jitter), (10, 8, exact.avg, None));
+        assert!(close(m.p90, exact.p90));
+    }
+}
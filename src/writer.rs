@@ -0,0 +1,170 @@
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender, TrySendError};
+use log::*;
+
+// Points held while the server is unreachable, the oldest are dropped beyond this
+const BUFFER: usize = 10_000;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(50);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// A server that a `Writer` sends batches of points to
+pub trait Destination<T>: Send + 'static {
+    /// For log messages, like `carbon at localhost:2003`
+    fn name(&self) -> String;
+    /// Write points in order. On error, also give how many of them were
+    /// written in full, they aren't sent again.
+    fn write(&mut self, points: &[T]) -> Result<(), (usize, io::Error)>;
+}
+
+/// Writes points to a destination from a thread of its own, so a slow or
+/// unreachable server doesn't hold up the caller. Points are buffered while
+/// it's unreachable, and writes are retried with backoff.
+pub struct Writer<T> {
+    points: Sender<T>,
+    name: String,
+    // points the thread couldn't keep up with
+    dropped: u64,
+}
+
+impl<T: Send + 'static> Writer<T> {
+    pub fn new<D: Destination<T>>(destination: D) -> Self {
+        let name = destination.name();
+        let (points, r) = bounded(BUFFER);
+        thread::spawn(move || run(destination, r));
+        Writer { points, name, dropped: 0 }
+    }
+
+    pub fn push(&mut self, point: T) {
+        if let Err(TrySendError::Full(_)) = self.points.try_send(point) {
+            self.dropped += 1;
+        }
+    }
+
+    /// Log any points dropped since the last call
+    pub fn report(&mut self) {
+        if self.dropped > 0 {
+            warn!("Dropped {} points that {} couldn't keep up with", self.dropped, self.name);
+            self.dropped = 0;
+        }
+    }
+}
+
+// Wait before retrying after this many failed writes in a row
+fn backoff(failures: u32) -> Duration {
+    (MIN_RETRY_DELAY * 2u32.pow(failures.min(10))).min(MAX_RETRY_DELAY)
+}
+
+fn run<T, D: Destination<T>>(mut destination: D, points: Receiver<T>) {
+    let mut buffer = vec![];
+    let mut dropped = 0;
+    let mut failures = 0;
+    let mut next_write = Instant::now() + FLUSH_INTERVAL;
+    loop {
+        let done = match points.recv_timeout(next_write.saturating_duration_since(Instant::now())) {
+            Ok(point) => {
+                buffer.push(point);
+                buffer.extend(points.try_iter());
+                if buffer.len() > BUFFER {
+                    let excess = buffer.len() - BUFFER;
+                    buffer.drain(..excess);
+                    dropped += excess;
+                }
+                if Instant::now() < next_write {
+                    continue;
+                }
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            // the sink is gone, write what's left, backing off if that fails
+            Err(RecvTimeoutError::Disconnected) => {
+                if failures > 0 {
+                    thread::sleep(next_write.saturating_duration_since(Instant::now()));
+                }
+                true
+            }
+        };
+
+        let now = Instant::now();
+        next_write = now + FLUSH_INTERVAL;
+        if buffer.is_empty() {
+            if done {
+                return;
+            }
+            continue;
+        }
+        if dropped > 0 {
+            warn!("Dropped {} points while {} was unreachable", dropped, destination.name());
+            dropped = 0;
+        }
+        match destination.write(&buffer) {
+            Ok(()) => {
+                buffer.clear();
+                failures = 0;
+            }
+            Err((written, e)) => {
+                buffer.drain(..written.min(buffer.len()));
+                failures += 1;
+                let delay = backoff(failures);
+                warn!("Unable to write to {}, retrying in {:?}: {}", destination.name(), delay, e);
+                next_write = now + delay;
+            }
+        }
+        if done && buffer.is_empty() {
+            return;
+        }
+    }
+}
+
+/// Write messages to a stream, returning how many were written in full on
+/// error. The stream should be closed after an error, so a message that was
+/// cut short is discarded rather than completed by the next write.
+pub fn write_messages<W: io::Write>(stream: &mut W, messages: &[Vec<u8>]) -> Result<(), (usize, io::Error)> {
+    for (i, message) in messages.iter().enumerate() {
+        stream.write_all(message).map_err(|e| (i, e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // Accepts the first two points of each batch, then fails
+    struct Flaky(Arc<Mutex<Vec<u32>>>);
+
+    impl Destination<u32> for Flaky {
+        fn name(&self) -> String {
+            "flaky".to_string()
+        }
+
+        fn write(&mut self, points: &[u32]) -> Result<(), (usize, io::Error)> {
+            let n = points.len().min(2);
+            self.0.lock().unwrap().extend_from_slice(&points[..n]);
+            if n < points.len() {
+                return Err((n, io::Error::new(io::ErrorKind::WriteZero, "full")));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn partial_writes() {
+        let written = Arc::new(Mutex::new(vec![]));
+        let mut writer = Writer::new(Flaky(written.clone()));
+        for i in 0..5 {
+            writer.push(i);
+        }
+        // written on the way out, and again after each failure
+        drop(writer);
+        let start = Instant::now();
+        while written.lock().unwrap().len() < 5 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(*written.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+    }
+}